@@ -1,11 +1,22 @@
 #!/usr/bin/env bash
 
-LD_LIBRARY_PATH=$APP_HOME/mame \
-  ./gipan \
+./gipan \
+  --mame-lib $APP_HOME/mame/libmame64.so \
   --imageframe-output $IPC_IMAGE_FRAMES \
   --soundframe-output $IPC_SOUND_FRAMES \
   --cmd-input $IPC_CMD_INPUTS \
   --resolution $RESOLUTION \
   --fps $FPS \
   --keyframe-interval $KEYFRAME_INTERVAL \
-  --game $GAME
+  --rompath $APP_HOME/roms \
+  --nvram-dir $APP_HOME/nvram \
+  --cfg-dir $APP_HOME/cfg \
+  --mame-option bgfx_path=$APP_HOME/bgfx \
+  ${LIBRETRO_CORE:+--emulator libretro --libretro-core $LIBRETRO_CORE} \
+  ${OPTIONS_FILE:+--options-file $OPTIONS_FILE} \
+  ${ROM_SOURCE:+--rom-source $ROM_SOURCE} \
+  ${INPUT_PROFILES:+--input-profiles $INPUT_PROFILES} \
+  ${REWIND_MEMORY:+--rewind-memory $REWIND_MEMORY} \
+  ${RUN_AHEAD:+--run-ahead $RUN_AHEAD} \
+  ${AUDIO_CHANNELS:+--audio-channels $AUDIO_CHANNELS} \
+  ${GAME:+--game $GAME}
@@ -0,0 +1,120 @@
+use std::mem;
+use std::ops::{Deref, DerefMut};
+
+use crossbeam_channel as channel;
+
+// Pool of reusable frame buffers shared by libemu and libenc. A buffer is sent back
+// through the pool's channel when dropped, so it gets recycled whichever thread the
+// frame ended up on. Buffers beyond the pool capacity are simply freed.
+#[derive(Clone)]
+pub struct BufferPool<T> {
+    free_tx: channel::Sender<Vec<T>>,
+    free_rx: channel::Receiver<Vec<T>>,
+}
+
+impl<T: Copy + Default> BufferPool<T> {
+    pub fn create(capacity: usize) -> BufferPool<T> {
+        let (free_tx, free_rx) = channel::bounded(capacity);
+        BufferPool {
+            free_tx: free_tx,
+            free_rx: free_rx,
+        }
+    }
+
+    // Returns a buffer of len elements. Contents left over from a previous use are not cleared.
+    pub fn acquire(&self, len: usize) -> PooledBuffer<T> {
+        let mut buf = self.free_rx.try_recv().unwrap_or_default();
+        buf.resize(len, T::default());
+        PooledBuffer {
+            buf: buf,
+            free_tx: Some(self.free_tx.clone()),
+        }
+    }
+
+    pub fn acquire_from(&self, src: &[T]) -> PooledBuffer<T> {
+        let mut buf = self.free_rx.try_recv().unwrap_or_default();
+        buf.clear();
+        buf.extend_from_slice(src);
+        PooledBuffer {
+            buf: buf,
+            free_tx: Some(self.free_tx.clone()),
+        }
+    }
+}
+
+pub struct PooledBuffer<T> {
+    buf: Vec<T>,
+    free_tx: Option<channel::Sender<Vec<T>>>,
+}
+
+impl<T> PooledBuffer<T> {
+    // Wraps a buffer which doesn't belong to any pool
+    pub fn from_vec(buf: Vec<T>) -> PooledBuffer<T> {
+        PooledBuffer {
+            buf: buf,
+            free_tx: None,
+        }
+    }
+}
+
+impl<T> Deref for PooledBuffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.buf
+    }
+}
+
+impl<T> DerefMut for PooledBuffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.buf
+    }
+}
+
+impl<T> AsRef<[T]> for PooledBuffer<T> {
+    fn as_ref(&self) -> &[T] {
+        &self.buf
+    }
+}
+
+impl<T> Drop for PooledBuffer<T> {
+    fn drop(&mut self) {
+        if let Some(free_tx) = self.free_tx.take() {
+            let _ = free_tx.try_send(mem::replace(&mut self.buf, Vec::new()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_buffers_are_handed_out_again() {
+        let pool = BufferPool::<u8>::create(2);
+        let buf = pool.acquire(1024);
+        let (ptr, capacity) = (buf.as_ptr(), buf.buf.capacity());
+        drop(buf);
+
+        let buf = pool.acquire_from(&[1, 2, 3]);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf.buf.capacity(), capacity);
+        assert_eq!(&*buf, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn buffers_beyond_capacity_are_freed() {
+        let pool = BufferPool::<u8>::create(1);
+        let bufs = vec![pool.acquire(16), pool.acquire(16), pool.acquire(16)];
+        drop(bufs);
+        assert_eq!(pool.free_rx.len(), 1);
+    }
+
+    #[test]
+    fn wrapped_buffers_stay_out_of_pools() {
+        let pool = BufferPool::<u8>::create(2);
+        let bufs = vec![pool.acquire(16), PooledBuffer::from_vec(vec![0u8; 16])];
+        drop(bufs);
+        assert_eq!(pool.free_rx.len(), 1);
+    }
+}
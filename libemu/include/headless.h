@@ -7,17 +7,49 @@
 extern "C" {
     typedef enum {
         INPUT_KEY_DOWN,
-        INPUT_KEY_UP
+        INPUT_KEY_UP,
+        INPUT_BUTTON_DOWN,
+        INPUT_BUTTON_UP,
+        INPUT_AXIS_ABSOLUTE,
+        INPUT_AXIS_RELATIVE,
+        INPUT_POINTER_MOVE
     } mame_input_enum_t;
 
+    // key holds a key code for INPUT_KEY_*, a button index for INPUT_BUTTON_*
+    // and an axis index for INPUT_AXIS_*. value is an absolute axis position
+    // in [-65536, 65536] or a relative delta, x and y are pointer coordinates
+    // in image frame pixels.
     typedef struct {
         uint8_t key;
         mame_input_enum_t type;
+        uint8_t player;
+        int32_t value;
+        int32_t x;
+        int32_t y;
     } mame_input_event_t;
 
+    typedef enum {
+        PIXEL_FORMAT_BGRA32,
+        PIXEL_FORMAT_RGBA32
+    } mame_pixel_format_t;
+
+    typedef enum {
+        ADDRESS_SPACE_PROGRAM,
+        ADDRESS_SPACE_DATA,
+        ADDRESS_SPACE_IO,
+        ADDRESS_SPACE_OPCODES
+    } mame_address_space_t;
+
+    // stride is the number of bytes per row, which may include padding after width pixels
     typedef struct {
         uint8_t *buffer;
         size_t buf_size;
+        uint64_t frame_number;
+        double refresh_rate;
+        int width;
+        int height;
+        int stride;
+        mame_pixel_format_t format;
     } mame_image_frame_t;
 
     typedef struct {
@@ -27,10 +59,56 @@ extern "C" {
         const int channels;
     } mame_sound_frame_t;
 
+    typedef enum {
+        ROM_STATUS_GOOD,
+        ROM_STATUS_NOT_FOUND,
+        ROM_STATUS_BAD_SIZE,
+        ROM_STATUS_BAD_CHECKSUM,
+        ROM_STATUS_NO_DUMP
+    } mame_rom_status_t;
+
+    // sizes are in bytes, actual_size is 0 for a rom which was not found. An optional rom
+    // doesn't keep the system from running when missing.
+    typedef struct {
+        const char *name;
+        mame_rom_status_t status;
+        uint64_t expected_size;
+        uint64_t actual_size;
+        int optional;
+    } mame_rom_audit_record_t;
+
+    typedef enum {
+        ORIENTATION_ROT0,
+        ORIENTATION_ROT90,
+        ORIENTATION_ROT180,
+        ORIENTATION_ROT270
+    } mame_orientation_t;
+
+    // strings are owned by mame and stay valid for the lifetime of the process
+    typedef struct {
+        const char *description;
+        const char *year;
+        const char *manufacturer;
+        int screen_width;
+        int screen_height;
+        mame_orientation_t orientation;
+        double refresh_rate;
+        int players;
+        int buttons;
+    } mame_system_info_t;
+
+    typedef enum {
+        RESET_SOFT,
+        RESET_HARD
+    } mame_reset_type_t;
+
+    typedef void (*mame_rom_audit_cb_t)(void *ctx, mame_rom_audit_record_t record);
     typedef void (*mame_image_frame_cb_t)(void *ctx, mame_image_frame_t frame);
     typedef void (*mame_sound_frame_cb_t)(void *ctx, mame_sound_frame_t frame);
+    typedef void (*mame_frame_begin_cb_t)(void *ctx, uint64_t frame_number);
 
     typedef struct {
+        // may also be called while running, frames of the new size follow from the next frame
         void (*set_image_frame_info)(int w, int h);
         void (*set_image_frame_cb)(void *ctx, mame_image_frame_cb_t frame_cb);
         void (*set_sound_frame_cb)(void *ctx, mame_sound_frame_cb_t frame_cb);
@@ -39,6 +117,63 @@ extern "C" {
         int (*run)(const char *system_name);
         void (*pause)();
         void (*resume)();
+
+        int (*save_state)(const char *slot_name);
+        int (*load_state)(const char *slot_name);
+        size_t (*get_state_size)();
+        int (*save_state_to_buffer)(uint8_t *buffer, size_t buf_size);
+        int (*load_state_from_buffer)(const uint8_t *buffer, size_t buf_size);
+
+        // unregister the frame callbacks. Like set_*_frame_cb, these return only after
+        // any callback in flight is done so that its ctx can be freed right away.
+        void (*clear_image_frame_cb)();
+        void (*clear_sound_frame_cb)();
+
+        // ask the running system to exit, which makes run() return
+        void (*stop)();
+
+        // speed is a multiplier of the system's native speed, 1.0 being realtime.
+        // Unthrottled, the system runs as fast as the host allows and speed is ignored.
+        void (*set_speed)(double speed);
+        void (*set_throttled)(int throttled);
+        // while paused, run exactly one more frame and stay paused
+        void (*step_frame)();
+
+        // called on the emulation thread before frame_number is emulated. Input events
+        // enqueued from within the callback are applied to that very frame.
+        void (*set_frame_begin_cb)(void *ctx, mame_frame_begin_cb_t frame_begin_cb);
+        const char *(*get_version)();
+
+        // access emulated memory of the cpu device tagged cpu_tag (ex, ":maincpu"), width being
+        // 1, 2, 4 or 8 bytes in the cpu's endianness. Accesses are serialized with emulation and
+        // happen in between frames. Return 0 on success, non zero for an unknown cpu or space.
+        int (*read_memory)(const char *cpu_tag, mame_address_space_t space, uint64_t address, int width, uint64_t *value);
+        int (*write_memory)(const char *cpu_tag, mame_address_space_t space, uint64_t address, int width, uint64_t value);
+
+        // audit the rom set of system_name in the rompath, calling audit_cb once per rom file
+        // before returning. Both return non zero for an unknown system and may be called
+        // without a system running.
+        int (*audit_system)(const char *system_name, void *ctx, mame_rom_audit_cb_t audit_cb);
+        int (*get_system_info)(const char *system_name, mame_system_info_t *info);
+
+        // set a mame option by its command line name without the dash (ex, "rompath"), taking
+        // effect from the next run() or load_system(). Returns non zero for an unknown option or invalid value.
+        int (*set_option)(const char *name, const char *value);
+
+        // a soft reset is the reset button of the cabinet and keeps the memory, a hard reset
+        // restarts the system as if powered on again. Both happen in between frames.
+        void (*reset)(mame_reset_type_t reset_type);
+        // switch the running machine to system_name in between frames, keeping run() going and
+        // the frame callbacks registered. Frame numbers start over from the new system.
+        // Returns non zero for an unknown system or when nothing is running.
+        int (*load_system)(const char *system_name);
+
+        // emulate one more frame from within the frame begin callback and return once it's done.
+        // Input events enqueued so far apply to it, and still apply to the frame about to begin,
+        // which is emulated after the callback returns as usual. Its image and sound frames are
+        // passed to the callbacks only if emit_image / emit_sound are non zero.
+        // Returns non zero outside of the frame begin callback.
+        int (*emulate_frame)(int emit_image, int emit_sound);
     } mame_t;
 
     mame_t* get_mame_instance();
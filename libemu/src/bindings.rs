@@ -509,18 +509,27 @@ fn bindgen_test_layout_max_align_t() {
 }
 pub const mame_input_enum_t_INPUT_KEY_DOWN: mame_input_enum_t = 0;
 pub const mame_input_enum_t_INPUT_KEY_UP: mame_input_enum_t = 1;
+pub const mame_input_enum_t_INPUT_BUTTON_DOWN: mame_input_enum_t = 2;
+pub const mame_input_enum_t_INPUT_BUTTON_UP: mame_input_enum_t = 3;
+pub const mame_input_enum_t_INPUT_AXIS_ABSOLUTE: mame_input_enum_t = 4;
+pub const mame_input_enum_t_INPUT_AXIS_RELATIVE: mame_input_enum_t = 5;
+pub const mame_input_enum_t_INPUT_POINTER_MOVE: mame_input_enum_t = 6;
 pub type mame_input_enum_t = u32;
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct mame_input_event_t {
     pub key: u8,
     pub type_: mame_input_enum_t,
+    pub player: u8,
+    pub value: i32,
+    pub x: i32,
+    pub y: i32,
 }
 #[test]
 fn bindgen_test_layout_mame_input_event_t() {
     assert_eq!(
         ::std::mem::size_of::<mame_input_event_t>(),
-        8usize,
+        24usize,
         concat!("Size of: ", stringify!(mame_input_event_t))
     );
     assert_eq!(
@@ -548,18 +557,72 @@ fn bindgen_test_layout_mame_input_event_t() {
             stringify!(type_)
         )
     );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_input_event_t>())).player as *const _ as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_input_event_t),
+            "::",
+            stringify!(player)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_input_event_t>())).value as *const _ as usize },
+        12usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_input_event_t),
+            "::",
+            stringify!(value)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_input_event_t>())).x as *const _ as usize },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_input_event_t),
+            "::",
+            stringify!(x)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_input_event_t>())).y as *const _ as usize },
+        20usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_input_event_t),
+            "::",
+            stringify!(y)
+        )
+    );
 }
+pub const mame_pixel_format_t_PIXEL_FORMAT_BGRA32: mame_pixel_format_t = 0;
+pub const mame_pixel_format_t_PIXEL_FORMAT_RGBA32: mame_pixel_format_t = 1;
+pub type mame_pixel_format_t = u32;
+pub const mame_address_space_t_ADDRESS_SPACE_PROGRAM: mame_address_space_t = 0;
+pub const mame_address_space_t_ADDRESS_SPACE_DATA: mame_address_space_t = 1;
+pub const mame_address_space_t_ADDRESS_SPACE_IO: mame_address_space_t = 2;
+pub const mame_address_space_t_ADDRESS_SPACE_OPCODES: mame_address_space_t = 3;
+pub type mame_address_space_t = u32;
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct mame_image_frame_t {
     pub buffer: *mut u8,
     pub buf_size: usize,
+    pub frame_number: u64,
+    pub refresh_rate: f64,
+    pub width: ::std::os::raw::c_int,
+    pub height: ::std::os::raw::c_int,
+    pub stride: ::std::os::raw::c_int,
+    pub format: mame_pixel_format_t,
 }
 #[test]
 fn bindgen_test_layout_mame_image_frame_t() {
     assert_eq!(
         ::std::mem::size_of::<mame_image_frame_t>(),
-        16usize,
+        48usize,
         concat!("Size of: ", stringify!(mame_image_frame_t))
     );
     assert_eq!(
@@ -587,6 +650,66 @@ fn bindgen_test_layout_mame_image_frame_t() {
             stringify!(buf_size)
         )
     );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_image_frame_t>())).frame_number as *const _ as usize },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_image_frame_t),
+            "::",
+            stringify!(frame_number)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_image_frame_t>())).refresh_rate as *const _ as usize },
+        24usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_image_frame_t),
+            "::",
+            stringify!(refresh_rate)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_image_frame_t>())).width as *const _ as usize },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_image_frame_t),
+            "::",
+            stringify!(width)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_image_frame_t>())).height as *const _ as usize },
+        36usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_image_frame_t),
+            "::",
+            stringify!(height)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_image_frame_t>())).stride as *const _ as usize },
+        40usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_image_frame_t),
+            "::",
+            stringify!(stride)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_image_frame_t>())).format as *const _ as usize },
+        44usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_image_frame_t),
+            "::",
+            stringify!(format)
+        )
+    );
 }
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -649,12 +772,228 @@ fn bindgen_test_layout_mame_sound_frame_t() {
         )
     );
 }
+pub const mame_rom_status_t_ROM_STATUS_GOOD: mame_rom_status_t = 0;
+pub const mame_rom_status_t_ROM_STATUS_NOT_FOUND: mame_rom_status_t = 1;
+pub const mame_rom_status_t_ROM_STATUS_BAD_SIZE: mame_rom_status_t = 2;
+pub const mame_rom_status_t_ROM_STATUS_BAD_CHECKSUM: mame_rom_status_t = 3;
+pub const mame_rom_status_t_ROM_STATUS_NO_DUMP: mame_rom_status_t = 4;
+pub type mame_rom_status_t = u32;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mame_rom_audit_record_t {
+    pub name: *const ::std::os::raw::c_char,
+    pub status: mame_rom_status_t,
+    pub expected_size: u64,
+    pub actual_size: u64,
+    pub optional: ::std::os::raw::c_int,
+}
+#[test]
+fn bindgen_test_layout_mame_rom_audit_record_t() {
+    assert_eq!(
+        ::std::mem::size_of::<mame_rom_audit_record_t>(),
+        40usize,
+        concat!("Size of: ", stringify!(mame_rom_audit_record_t))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<mame_rom_audit_record_t>(),
+        8usize,
+        concat!("Alignment of ", stringify!(mame_rom_audit_record_t))
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_rom_audit_record_t>())).name as *const _ as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_rom_audit_record_t),
+            "::",
+            stringify!(name)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_rom_audit_record_t>())).status as *const _ as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_rom_audit_record_t),
+            "::",
+            stringify!(status)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::std::ptr::null::<mame_rom_audit_record_t>())).expected_size as *const _ as usize
+        },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_rom_audit_record_t),
+            "::",
+            stringify!(expected_size)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::std::ptr::null::<mame_rom_audit_record_t>())).actual_size as *const _ as usize
+        },
+        24usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_rom_audit_record_t),
+            "::",
+            stringify!(actual_size)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::std::ptr::null::<mame_rom_audit_record_t>())).optional as *const _ as usize
+        },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_rom_audit_record_t),
+            "::",
+            stringify!(optional)
+        )
+    );
+}
+pub const mame_orientation_t_ORIENTATION_ROT0: mame_orientation_t = 0;
+pub const mame_orientation_t_ORIENTATION_ROT90: mame_orientation_t = 1;
+pub const mame_orientation_t_ORIENTATION_ROT180: mame_orientation_t = 2;
+pub const mame_orientation_t_ORIENTATION_ROT270: mame_orientation_t = 3;
+pub type mame_orientation_t = u32;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mame_system_info_t {
+    pub description: *const ::std::os::raw::c_char,
+    pub year: *const ::std::os::raw::c_char,
+    pub manufacturer: *const ::std::os::raw::c_char,
+    pub screen_width: ::std::os::raw::c_int,
+    pub screen_height: ::std::os::raw::c_int,
+    pub orientation: mame_orientation_t,
+    pub refresh_rate: f64,
+    pub players: ::std::os::raw::c_int,
+    pub buttons: ::std::os::raw::c_int,
+}
+#[test]
+fn bindgen_test_layout_mame_system_info_t() {
+    assert_eq!(
+        ::std::mem::size_of::<mame_system_info_t>(),
+        56usize,
+        concat!("Size of: ", stringify!(mame_system_info_t))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<mame_system_info_t>(),
+        8usize,
+        concat!("Alignment of ", stringify!(mame_system_info_t))
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_system_info_t>())).description as *const _ as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_system_info_t),
+            "::",
+            stringify!(description)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_system_info_t>())).year as *const _ as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_system_info_t),
+            "::",
+            stringify!(year)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_system_info_t>())).manufacturer as *const _ as usize },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_system_info_t),
+            "::",
+            stringify!(manufacturer)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_system_info_t>())).screen_width as *const _ as usize },
+        24usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_system_info_t),
+            "::",
+            stringify!(screen_width)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::std::ptr::null::<mame_system_info_t>())).screen_height as *const _ as usize
+        },
+        28usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_system_info_t),
+            "::",
+            stringify!(screen_height)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_system_info_t>())).orientation as *const _ as usize },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_system_info_t),
+            "::",
+            stringify!(orientation)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_system_info_t>())).refresh_rate as *const _ as usize },
+        40usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_system_info_t),
+            "::",
+            stringify!(refresh_rate)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_system_info_t>())).players as *const _ as usize },
+        48usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_system_info_t),
+            "::",
+            stringify!(players)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_system_info_t>())).buttons as *const _ as usize },
+        52usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_system_info_t),
+            "::",
+            stringify!(buttons)
+        )
+    );
+}
+pub const mame_reset_type_t_RESET_SOFT: mame_reset_type_t = 0;
+pub const mame_reset_type_t_RESET_HARD: mame_reset_type_t = 1;
+pub type mame_reset_type_t = u32;
+pub type mame_rom_audit_cb_t = ::std::option::Option<
+    unsafe extern "C" fn(ctx: *mut ::std::os::raw::c_void, record: mame_rom_audit_record_t),
+>;
 pub type mame_image_frame_cb_t = ::std::option::Option<
     unsafe extern "C" fn(ctx: *mut ::std::os::raw::c_void, frame: mame_image_frame_t),
 >;
 pub type mame_sound_frame_cb_t = ::std::option::Option<
     unsafe extern "C" fn(ctx: *mut ::std::os::raw::c_void, frame: mame_sound_frame_t),
 >;
+pub type mame_frame_begin_cb_t = ::std::option::Option<
+    unsafe extern "C" fn(ctx: *mut ::std::os::raw::c_void, frame_number: u64),
+>;
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct mame_t {
@@ -674,12 +1013,86 @@ pub struct mame_t {
     >,
     pub pause: ::std::option::Option<unsafe extern "C" fn()>,
     pub resume: ::std::option::Option<unsafe extern "C" fn()>,
+    pub save_state: ::std::option::Option<
+        unsafe extern "C" fn(slot_name: *const ::std::os::raw::c_char) -> ::std::os::raw::c_int,
+    >,
+    pub load_state: ::std::option::Option<
+        unsafe extern "C" fn(slot_name: *const ::std::os::raw::c_char) -> ::std::os::raw::c_int,
+    >,
+    pub get_state_size: ::std::option::Option<unsafe extern "C" fn() -> usize>,
+    pub save_state_to_buffer: ::std::option::Option<
+        unsafe extern "C" fn(buffer: *mut u8, buf_size: usize) -> ::std::os::raw::c_int,
+    >,
+    pub load_state_from_buffer: ::std::option::Option<
+        unsafe extern "C" fn(buffer: *const u8, buf_size: usize) -> ::std::os::raw::c_int,
+    >,
+    pub clear_image_frame_cb: ::std::option::Option<unsafe extern "C" fn()>,
+    pub clear_sound_frame_cb: ::std::option::Option<unsafe extern "C" fn()>,
+    pub stop: ::std::option::Option<unsafe extern "C" fn()>,
+    pub set_speed: ::std::option::Option<unsafe extern "C" fn(speed: f64)>,
+    pub set_throttled:
+        ::std::option::Option<unsafe extern "C" fn(throttled: ::std::os::raw::c_int)>,
+    pub step_frame: ::std::option::Option<unsafe extern "C" fn()>,
+    pub set_frame_begin_cb: ::std::option::Option<
+        unsafe extern "C" fn(
+            ctx: *mut ::std::os::raw::c_void,
+            frame_begin_cb: mame_frame_begin_cb_t,
+        ),
+    >,
+    pub get_version: ::std::option::Option<unsafe extern "C" fn() -> *const ::std::os::raw::c_char>,
+    pub read_memory: ::std::option::Option<
+        unsafe extern "C" fn(
+            cpu_tag: *const ::std::os::raw::c_char,
+            space: mame_address_space_t,
+            address: u64,
+            width: ::std::os::raw::c_int,
+            value: *mut u64,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub write_memory: ::std::option::Option<
+        unsafe extern "C" fn(
+            cpu_tag: *const ::std::os::raw::c_char,
+            space: mame_address_space_t,
+            address: u64,
+            width: ::std::os::raw::c_int,
+            value: u64,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub audit_system: ::std::option::Option<
+        unsafe extern "C" fn(
+            system_name: *const ::std::os::raw::c_char,
+            ctx: *mut ::std::os::raw::c_void,
+            audit_cb: mame_rom_audit_cb_t,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub get_system_info: ::std::option::Option<
+        unsafe extern "C" fn(
+            system_name: *const ::std::os::raw::c_char,
+            info: *mut mame_system_info_t,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub set_option: ::std::option::Option<
+        unsafe extern "C" fn(
+            name: *const ::std::os::raw::c_char,
+            value: *const ::std::os::raw::c_char,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub reset: ::std::option::Option<unsafe extern "C" fn(reset_type: mame_reset_type_t)>,
+    pub load_system: ::std::option::Option<
+        unsafe extern "C" fn(system_name: *const ::std::os::raw::c_char) -> ::std::os::raw::c_int,
+    >,
+    pub emulate_frame: ::std::option::Option<
+        unsafe extern "C" fn(
+            emit_image: ::std::os::raw::c_int,
+            emit_sound: ::std::os::raw::c_int,
+        ) -> ::std::os::raw::c_int,
+    >,
 }
 #[test]
 fn bindgen_test_layout_mame_t() {
     assert_eq!(
         ::std::mem::size_of::<mame_t>(),
-        56usize,
+        224usize,
         concat!("Size of: ", stringify!(mame_t))
     );
     assert_eq!(
@@ -757,7 +1170,214 @@ fn bindgen_test_layout_mame_t() {
             stringify!(resume)
         )
     );
-}
-extern "C" {
-    pub fn get_mame_instance() -> *mut mame_t;
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).save_state as *const _ as usize },
+        56usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(save_state)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).load_state as *const _ as usize },
+        64usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(load_state)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).get_state_size as *const _ as usize },
+        72usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(get_state_size)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).save_state_to_buffer as *const _ as usize },
+        80usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(save_state_to_buffer)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).load_state_from_buffer as *const _ as usize },
+        88usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(load_state_from_buffer)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).clear_image_frame_cb as *const _ as usize },
+        96usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(clear_image_frame_cb)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).clear_sound_frame_cb as *const _ as usize },
+        104usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(clear_sound_frame_cb)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).stop as *const _ as usize },
+        112usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(stop)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).set_speed as *const _ as usize },
+        120usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(set_speed)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).set_throttled as *const _ as usize },
+        128usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(set_throttled)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).step_frame as *const _ as usize },
+        136usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(step_frame)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).set_frame_begin_cb as *const _ as usize },
+        144usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(set_frame_begin_cb)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).get_version as *const _ as usize },
+        152usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(get_version)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).read_memory as *const _ as usize },
+        160usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(read_memory)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).write_memory as *const _ as usize },
+        168usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(write_memory)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).audit_system as *const _ as usize },
+        176usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(audit_system)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).get_system_info as *const _ as usize },
+        184usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(get_system_info)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).set_option as *const _ as usize },
+        192usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(set_option)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).reset as *const _ as usize },
+        200usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(reset)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).load_system as *const _ as usize },
+        208usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(load_system)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<mame_t>())).emulate_frame as *const _ as usize },
+        216usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mame_t),
+            "::",
+            stringify!(emulate_frame)
+        )
+    );
 }
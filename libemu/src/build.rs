@@ -7,6 +7,8 @@ fn main() {
         .header("include/headless.h")
         .clang_arg("-xc++")
         .clang_arg("-std=c++14")
+        // libmame is loaded at runtime, where get_mame_instance is looked up by name
+        .blacklist_function("get_mame_instance")
         .parse_callbacks(Box::new(bindgen::CargoCallbacks))
         .generate()
         .expect("Unable to generate bindings");
@@ -14,8 +16,4 @@ fn main() {
     bindings
         .write_to_file(PathBuf::from("src/bindings.rs"))
         .expect("Couldn't write bindings!");
-
-    const MAME_HOME: &str = "./mame";
-    println!("cargo:rustc-link-lib=dylib=mame64");
-    println!("cargo:rustc-link-search=native={}", MAME_HOME);
  }
\ No newline at end of file
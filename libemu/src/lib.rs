@@ -7,103 +7,590 @@ use std::slice;
 use std::mem;
 use std::ops::Sub;
 use std::time::{SystemTime, Duration, UNIX_EPOCH};
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 use libc::*;
 
+use libbuf::{BufferPool, PooledBuffer};
+
 mod utils;
+mod lifecycle;
+mod pattern;
+mod replay;
+mod system;
+mod options;
+mod macros;
+mod libretro;
+mod rewind;
+
+pub use lifecycle::{EmuEvent, EmuExitReason, EmuExitStatus, EmuRunHandle};
+pub use pattern::PatternEmulator;
+pub use libretro::LibretroEmulator;
+pub use replay::{InputMode, Replay, ReplayHeader, ReplayInput, ReplayWriter};
+pub use system::{Orientation, RomAudit, RomAuditRecord, RomStatus, SystemInfo};
+pub use options::EmulatorOptions;
+pub use macros::MacroStep;
+pub use rewind::{RewindSettings, DEFAULT_REWIND_INTERVAL, DEFAULT_REWIND_MEMORY_LIMIT};
 
 include!("./bindings.rs");
 
-#[derive(Debug)]
+// mame_t methods are all checked to be present when libmame is loaded
+macro_rules! mame_fn {
+    ($mame:expr, $method:ident) => {
+        (*$mame).$method.expect(concat!(stringify!($method), " is checked when libmame is loaded"))
+    };
+}
+
+#[cfg(target_os = "macos")]
+pub const DEFAULT_MAME_LIB_PATH: &str = "./mame/libmame64.dylib";
+#[cfg(not(target_os = "macos"))]
+pub const DEFAULT_MAME_LIB_PATH: &str = "./mame/libmame64.so";
+
+type GetMameInstanceFn = unsafe extern "C" fn() -> *mut mame_t;
+
+// Range of absolute axis positions, same as MAME's analog inputs
+pub const INPUT_AXIS_MAX: i32 = 65536;
+pub const INPUT_AXIS_MIN: i32 = -65536;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum InputKind {
     INPUT_KEY_DOWN,
     INPUT_KEY_UP,
+    INPUT_BUTTON_DOWN,
+    INPUT_BUTTON_UP,
+    INPUT_AXIS_ABSOLUTE,
+    INPUT_AXIS_RELATIVE,
+    INPUT_POINTER_MOVE,
 }
 
-#[derive(Debug)]
+// value is a key code, a button index or an axis index depending on kind.
+// analog carries the axis position (or delta) and x, y the pointer position.
+#[derive(Debug, Clone)]
 pub struct EmuInputEvent {
+    pub player: u8,
     pub value: u8,
     pub kind: InputKind,
+    pub analog: i32,
+    pub x: i32,
+    pub y: i32,
 }
 
+impl EmuInputEvent {
+    pub fn key(value: u8, kind: InputKind) -> EmuInputEvent {
+        EmuInputEvent { player: 0, value: value, kind: kind, analog: 0, x: 0, y: 0 }
+    }
+
+    pub fn button(player: u8, button: u8, kind: InputKind) -> EmuInputEvent {
+        EmuInputEvent { player: player, value: button, kind: kind, analog: 0, x: 0, y: 0 }
+    }
+
+    pub fn axis(player: u8, axis: u8, kind: InputKind, analog: i32) -> EmuInputEvent {
+        let analog = match kind {
+            InputKind::INPUT_AXIS_ABSOLUTE => analog.max(INPUT_AXIS_MIN).min(INPUT_AXIS_MAX),
+            _ => analog,
+        };
+        EmuInputEvent { player: player, value: axis, kind: kind, analog: analog, x: 0, y: 0 }
+    }
+
+    pub fn pointer(player: u8, x: i32, y: i32) -> EmuInputEvent {
+        EmuInputEvent {
+            player: player,
+            value: 0,
+            kind: InputKind::INPUT_POINTER_MOVE,
+            analog: 0,
+            x: x,
+            y: y,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PixelFormat {
+    PIXEL_FORMAT_BGRA32,
+    PIXEL_FORMAT_RGBA32,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::PIXEL_FORMAT_BGRA32 | PixelFormat::PIXEL_FORMAT_RGBA32 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AddressSpace {
+    ADDRESS_SPACE_PROGRAM,
+    ADDRESS_SPACE_DATA,
+    ADDRESS_SPACE_IO,
+    ADDRESS_SPACE_OPCODES,
+}
+
+// A soft reset keeps the memory of the system, a hard reset powers it on again
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ResetKind {
+    RESET_SOFT,
+    RESET_HARD,
+}
+
+// width is the access size in bytes, one of 1, 2, 4 or 8
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryLocation {
+    pub cpu_tag: String,
+    pub space: AddressSpace,
+    pub address: u64,
+    pub width: usize,
+}
+
+impl MemoryLocation {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.width {
+            1 | 2 | 4 | 8 => Ok(()),
+            _ => Err(format!("invalid memory access width: {}", self.width)),
+        }
+    }
+}
+
+// stride is the number of bytes per row of buf, frame_number the emulated frame
+// the image was taken from. discontinuity is set on the first frame after a reset or
+// a system switch, which doesn't follow on from the frames before it.
+// timestamp is the wall clock time the frame was handed out at, to tell stale frames by.
+// emu_timestamp is the presentation time on the emulated clock, which sound frames share.
 pub struct EmuImageFrame {
-    pub buf: Vec<u8>,
+    pub buf: PooledBuffer<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub format: PixelFormat,
+    pub frame_number: u64,
     pub timestamp: Duration,
+    pub emu_timestamp: Duration,
+    pub discontinuity: bool,
 }
 
+// buf holds samples interleaved by channel. emu_timestamp counts the samples emitted so far,
+// lined up with the emu_timestamp of image frames.
 pub struct EmuSoundFrame {
-    pub buf: Vec<i16>,
+    pub buf: PooledBuffer<i16>,
     pub sample_rate: usize,
     pub samples: usize,
     pub channels: usize,
     pub timestamp: Duration,
+    pub emu_timestamp: Duration,
+}
+
+// Keeps a frame callback registered. Dropping it unregisters the callback and frees it.
+#[must_use = "dropping the guard unregisters the callback"]
+pub struct EmuCallbackGuard {
+    release: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl EmuCallbackGuard {
+    fn new(release: impl FnOnce() + Send + 'static) -> EmuCallbackGuard {
+        EmuCallbackGuard {
+            release: Some(Box::new(release)),
+        }
+    }
+}
+
+impl Drop for EmuCallbackGuard {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
+
+// number of recycled frame buffers kept around per pool
+const FRAME_POOL_SIZE: usize = 16;
+
+// Range of speed multipliers, 1.0 being the native speed of the system
+pub const SPEED_MIN: f64 = 0.1;
+pub const SPEED_MAX: f64 = 10.0;
+
+// Upper bound of the run_ahead option, every frame ahead is emulated again on each frame
+pub const MAX_RUN_AHEAD_FRAMES: u32 = 6;
+
+fn validate_speed(speed: f64) -> Result<(), String> {
+    if speed >= SPEED_MIN && speed <= SPEED_MAX {
+        Ok(())
+    } else {
+        Err(format!("speed {} is out of range [{}, {}]", speed, SPEED_MIN, SPEED_MAX))
+    }
+}
+
+// Slot names end up in file names, so they can't reach out of the state directory
+fn validate_slot_name(slot_name: &str) -> Result<(), String> {
+    let invalid = slot_name.is_empty()
+        || slot_name.contains(|c| c == '/' || c == '\\' || c == '\0')
+        || slot_name.contains("..");
+    if invalid {
+        Err(format!("invalid slot name: {:?}", slot_name))
+    } else {
+        Ok(())
+    }
 }
 
 pub trait Emulator: Clone + Send {
     fn set_image_frame_info(&mut self, w: usize, h: usize, fps: usize);
-    fn set_image_frame_cb(&mut self, callback: impl FnMut(EmuImageFrame));
-    fn set_sound_frame_cb(&mut self, callback: impl FnMut(EmuSoundFrame));
+    fn set_image_frame_cb(&mut self, callback: impl FnMut(EmuImageFrame) + Send + 'static) -> EmuCallbackGuard;
+    fn set_sound_frame_cb(&mut self, callback: impl FnMut(EmuSoundFrame) + Send + 'static) -> EmuCallbackGuard;
+    fn set_options(&mut self, options: &EmulatorOptions) -> Result<(), String>;
     fn put_input_event(&self, event: EmuInputEvent);
-    fn run(&self, system_name: &str) -> i32;
+    fn set_input_mode(&self, mode: InputMode);
+    fn set_turbo(&self, player: u8, button: u8, rate: f64) -> Result<(), String>;
+    fn set_macro(&self, player: u8, name: &str, steps: Vec<MacroStep>);
+    fn run_macro(&self, player: u8, name: &str) -> Result<(), String>;
+    fn version(&self) -> String;
+    fn run(&self, system_name: &str) -> EmuRunHandle;
+    fn stop(&self);
     fn pause(&self);
     fn resume(&self);
+    fn set_speed(&self, speed: f64) -> Result<(), String>;
+    fn set_throttled(&self, throttled: bool);
+    fn step_frame(&self) -> Result<(), String>;
+    fn reset(&self, kind: ResetKind);
+    fn load_system(&self, system_name: &str) -> Result<(), String>;
+    fn set_rewind(&self, settings: Option<RewindSettings>) -> Result<(), String>;
+    fn rewind(&self, seconds: f64) -> Result<(), String>;
+    fn save_state(&self, slot_name: &str) -> Result<(), String>;
+    fn load_state(&self, slot_name: &str) -> Result<(), String>;
+    fn save_state_data(&self) -> Result<Vec<u8>, String>;
+    fn load_state_data(&self, data: &[u8]) -> Result<(), String>;
+    fn read_memory(&self, location: &MemoryLocation) -> Result<u64, String>;
+    fn write_memory(&self, location: &MemoryLocation, value: u64) -> Result<(), String>;
+    fn audit_system(&self, system_name: &str) -> Result<RomAudit, String>;
+    fn system_info(&self, system_name: &str) -> Result<SystemInfo, String>;
 }
 
 #[derive(Clone)]
 pub struct MameEmulator {
+    // keeps libmame loaded for as long as mame_inst is in use
+    _lib: Arc<libloading::Library>,
     mame_inst: *mut mame_t,
-    fps: usize,
-    last_updated: Duration,
+    pacer: Arc<Mutex<utils::pacing::FramePacer>>,
+    discontinuity: Arc<AtomicBool>,
+    image_cb_registration: Arc<Mutex<u64>>,
+    sound_cb_registration: Arc<Mutex<u64>>,
+    lifecycle: lifecycle::Lifecycle,
+    inputs: Arc<Mutex<replay::InputScheduler>>,
+    rewinder: Arc<Mutex<rewind::Rewinder>>,
+    run_ahead: Arc<MameRunAhead>,
+    // state to load at the beginning of the next frame, see apply_pending_load
+    pending_load: Arc<Mutex<Option<Vec<u8>>>>,
+    image_pool: BufferPool<u8>,
+    sound_pool: BufferPool<i16>,
 }
 
 unsafe impl Send for MameEmulator {}
 
+// With run-ahead, the state is saved before each frame and the frames after it are emulated
+// with the inputs so far. Only the image of the last of them is shown, then the state is rolled
+// back and the frame is emulated for real, its image hidden but its sound played, so that
+// sound only ever comes from the real timeline.
+#[derive(Default)]
+struct MameRunAhead {
+    // frames emulated ahead, 0 being off
+    frames: AtomicU32,
+    // set while emulating ahead
+    speculating: AtomicBool,
+    // set when the image of the real frame is replaced by the one from ahead
+    hide_real_frame: AtomicBool,
+    // real frame the image from ahead is shown for
+    frame_number: AtomicU64,
+    // bumped by every state load, which a rollback to the state saved before must not undo
+    loads: AtomicU64,
+}
+
 impl MameEmulator {
-    pub fn create(w: usize, h: usize, fps: usize) -> impl Emulator {
-        let mame_inst: *mut mame_t = unsafe { get_mame_instance() };
+    pub fn create(lib_path: &str, w: usize, h: usize, fps: usize) -> Result<impl Emulator, String> {
+        let (lib, mame_inst) = mame_load(lib_path)?;
+        let lifecycle = lifecycle::Lifecycle::default();
         let mut emu = MameEmulator {
+            _lib: Arc::new(lib),
             mame_inst: mame_inst,
-            fps: 24,
-            last_updated: utils::time::now_utc(),
+            pacer: Arc::new(Mutex::new(utils::pacing::FramePacer::new(fps))),
+            discontinuity: Arc::new(AtomicBool::new(false)),
+            image_cb_registration: Arc::new(Mutex::new(0)),
+            sound_cb_registration: Arc::new(Mutex::new(0)),
+            lifecycle: lifecycle.clone(),
+            inputs: Arc::new(Mutex::new(replay::InputScheduler::new(lifecycle))),
+            rewinder: Arc::new(Mutex::new(rewind::Rewinder::new())),
+            run_ahead: Arc::new(MameRunAhead::default()),
+            pending_load: Arc::new(Mutex::new(None)),
+            image_pool: BufferPool::create(FRAME_POOL_SIZE),
+            sound_pool: BufferPool::create(FRAME_POOL_SIZE),
         };
 
         emu.set_image_frame_info(w, h, fps);
-        emu
+        Ok(emu)
     }
 
-    fn should_update_image_frame(&mut self) -> bool {
-        let frame_ms = Duration::from_millis((1000 / self.fps) as u64);
-        let delta = utils::time::now_utc().sub(self.last_updated);
-        let should_update = delta >= frame_ms;
-        if should_update {
-            self.last_updated = utils::time::now_utc();
+    // libmame doesn't serialize loading a state from a buffer with emulation, so it's left to
+    // the emulation thread in between frames, before run-ahead saves the state of the frame
+    fn queue_state_load(&self, data: Vec<u8>) -> Result<(), String> {
+        let state_size = unsafe {
+            mame_fn!(self.mame_inst, get_state_size)()
+        };
+        if state_size == 0 {
+            return Err(format!("state is not available for the running system"));
+        }
+        if data.len() != state_size {
+            return Err(format!("state of {} bytes doesn't match the {} bytes of the running system", data.len(), state_size));
         }
-        should_update
+        *self.pending_load.lock().unwrap() = Some(data);
+        Ok(())
     }
+
+    // Called from the frame begin callback
+    fn apply_pending_load(&self) {
+        let data = match self.pending_load.lock().unwrap().take() {
+            Some(data) => data,
+            None => return,
+        };
+        let ret = unsafe {
+            mame_fn!(self.mame_inst, load_state_from_buffer)(data.as_ptr(), data.len())
+        };
+        if ret != 0 {
+            println!("failed to load state from buffer: {}", ret);
+            return;
+        }
+        self.run_ahead.loads.fetch_add(1, Ordering::SeqCst);
+        self.discontinuity.store(true, Ordering::SeqCst);
+    }
+}
+
+fn mame_load(lib_path: &str) -> Result<(libloading::Library, *mut mame_t), String> {
+    let lib = libloading::Library::new(lib_path)
+        .map_err(|e| format!("failed to load libmame from {}: {}", lib_path, e))?;
+    let mame_inst = unsafe {
+        let get_mame_instance = lib.get::<GetMameInstanceFn>(b"get_mame_instance\0")
+            .map_err(|e| format!("{} is not a headless libmame: {}", lib_path, e))?;
+        get_mame_instance()
+    };
+    if mame_inst.is_null() {
+        return Err(format!("libmame at {} returned no instance", lib_path));
+    }
+
+    let missing = mame_missing_methods(unsafe { &*mame_inst });
+    if !missing.is_empty() {
+        return Err(format!("libmame at {} doesn't implement: {}", lib_path, missing.join(", ")));
+    }
+    Ok((lib, mame_inst))
 }
 
+// e.g. a libmame built against an older headless.h
+fn mame_missing_methods(mame: &mame_t) -> Vec<&'static str> {
+    let methods = [
+        ("set_image_frame_info", mame.set_image_frame_info.is_some()),
+        ("set_image_frame_cb", mame.set_image_frame_cb.is_some()),
+        ("set_sound_frame_cb", mame.set_sound_frame_cb.is_some()),
+        ("enqueue_input_event", mame.enqueue_input_event.is_some()),
+        ("run", mame.run.is_some()),
+        ("pause", mame.pause.is_some()),
+        ("resume", mame.resume.is_some()),
+        ("save_state", mame.save_state.is_some()),
+        ("load_state", mame.load_state.is_some()),
+        ("get_state_size", mame.get_state_size.is_some()),
+        ("save_state_to_buffer", mame.save_state_to_buffer.is_some()),
+        ("load_state_from_buffer", mame.load_state_from_buffer.is_some()),
+        ("clear_image_frame_cb", mame.clear_image_frame_cb.is_some()),
+        ("clear_sound_frame_cb", mame.clear_sound_frame_cb.is_some()),
+        ("stop", mame.stop.is_some()),
+        ("set_speed", mame.set_speed.is_some()),
+        ("set_throttled", mame.set_throttled.is_some()),
+        ("step_frame", mame.step_frame.is_some()),
+        ("set_frame_begin_cb", mame.set_frame_begin_cb.is_some()),
+        ("get_version", mame.get_version.is_some()),
+        ("read_memory", mame.read_memory.is_some()),
+        ("write_memory", mame.write_memory.is_some()),
+        ("audit_system", mame.audit_system.is_some()),
+        ("get_system_info", mame.get_system_info.is_some()),
+        ("set_option", mame.set_option.is_some()),
+        ("reset", mame.reset.is_some()),
+        ("load_system", mame.load_system.is_some()),
+        ("emulate_frame", mame.emulate_frame.is_some()),
+    ];
+    methods.iter()
+        .filter(|(_, present)| !present)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+struct SendPtr<T>(*mut T);
+
+unsafe impl<T> Send for SendPtr<T> {}
+
 // https://blog.seantheprogrammer.com/neat-rust-tricks-passing-rust-closures-to-c
-fn mame_register_frame_cb<F>(mame: *mut mame_t, callback: F)
-where F: FnMut(mame_image_frame_t), {
+fn mame_register_frame_cb<F>(mame: *mut mame_t, registration: Arc<Mutex<u64>>, callback: F) -> EmuCallbackGuard
+where F: FnMut(mame_image_frame_t) + Send + 'static, {
     let ctx = Box::into_raw(Box::new(callback));
-    unsafe {
-        match (*mame).set_image_frame_cb {
-            Some(f) => f(ctx as *mut _, Some(mame_image_cb_closure::<F>)),
-            None => panic!("set_frame_cb method is not implemented.")
+    let (clear_cb, id) = unsafe {
+        let mut current = registration.lock().unwrap();
+        mame_fn!(mame, set_image_frame_cb)(ctx as *mut _, Some(mame_image_cb_closure::<F>));
+        *current += 1;
+        (mame_fn!(mame, clear_image_frame_cb), *current)
+    };
+    mame_unregister_cb_guard(SendPtr(ctx), registration, id, clear_cb)
+}
+
+fn mame_register_sound_cb<F>(mame: *mut mame_t, registration: Arc<Mutex<u64>>, callback: F) -> EmuCallbackGuard
+where F: FnMut(mame_sound_frame_t) + Send + 'static, {
+    let ctx = Box::into_raw(Box::new(callback));
+    let (clear_cb, id) = unsafe {
+        let mut current = registration.lock().unwrap();
+        mame_fn!(mame, set_sound_frame_cb)(ctx as *mut _, Some(mame_sound_cb_closure::<F>));
+        *current += 1;
+        (mame_fn!(mame, clear_sound_frame_cb), *current)
+    };
+    mame_unregister_cb_guard(SendPtr(ctx), registration, id, clear_cb)
+}
+
+// The callback is cleared from mame only if it hasn't been replaced by a later registration.
+// Either way mame no longer calls it afterwards, so the closure can be freed.
+fn mame_unregister_cb_guard<F>(
+    ctx: SendPtr<F>,
+    registration: Arc<Mutex<u64>>,
+    id: u64,
+    clear_cb: unsafe extern "C" fn()) -> EmuCallbackGuard
+where F: Send + 'static, {
+    EmuCallbackGuard::new(move || {
+        let current = registration.lock().unwrap();
+        unsafe {
+            if *current == id {
+                clear_cb();
+            }
+            drop(Box::from_raw(ctx.0));
         }
-    }
+    })
 }
 
-fn mame_register_sound_cb<F>(mame: *mut mame_t, callback: F)
-where F: FnMut(mame_sound_frame_t), {
+// Runs system_name with the frame begin callback registered for the duration of the run.
+fn mame_run_with_frame_begin_cb<F>(mame: *mut mame_t, system_name: &CString, callback: F) -> i32
+where F: FnMut(u64), {
     let ctx = Box::into_raw(Box::new(callback));
     unsafe {
-        match (*mame).set_sound_frame_cb {
-            Some(f) => f(ctx as *mut _, Some(mame_sound_cb_closure::<F>)),
-            None => panic!("set_frame_cb method is not implemented.")
+        mame_fn!(mame, set_frame_begin_cb)(ctx as *mut _, Some(mame_frame_begin_cb_closure::<F>));
+        let ret = mame_fn!(mame, run)(system_name.as_ptr() as *const c_char);
+        mame_fn!(mame, set_frame_begin_cb)(std::ptr::null_mut(), None);
+        drop(Box::from_raw(ctx));
+        ret
+    }
+}
+
+// Called from the frame begin callback, state_buf being kept across frames to save the state into.
+// Returns whether an image from ahead was shown for the frame about to begin.
+fn mame_run_ahead(mame: *mut mame_t, run_ahead: &MameRunAhead, frame_number: u64, state_buf: &mut Vec<u8>) -> bool {
+    let frames = run_ahead.frames.load(Ordering::SeqCst);
+    if frames == 0 {
+        return false;
+    }
+
+    // the state isn't available until the system has started
+    let state_size = unsafe { mame_fn!(mame, get_state_size)() };
+    if state_size == 0 {
+        return false;
+    }
+    state_buf.resize(state_size, 0);
+    let loads = run_ahead.loads.load(Ordering::SeqCst);
+    let ret = unsafe { mame_fn!(mame, save_state_to_buffer)(state_buf.as_mut_ptr(), state_buf.len()) };
+    if ret != 0 {
+        println!("run-ahead failed to save state: {}", ret);
+        return false;
+    }
+
+    run_ahead.frame_number.store(frame_number, Ordering::SeqCst);
+    run_ahead.speculating.store(true, Ordering::SeqCst);
+    let mut emulated = true;
+    for i in 0..frames {
+        let emit_image = if i + 1 == frames { 1 } else { 0 };
+        if unsafe { mame_fn!(mame, emulate_frame)(emit_image, 0) } != 0 {
+            emulated = false;
+            break;
         }
     }
+    run_ahead.speculating.store(false, Ordering::SeqCst);
+
+    // a slot loaded by mame while emulating ahead is newer than the state saved before
+    if run_ahead.loads.load(Ordering::SeqCst) != loads {
+        return emulated;
+    }
+    let ret = unsafe { mame_fn!(mame, load_state_from_buffer)(state_buf.as_ptr(), state_buf.len()) };
+    if ret != 0 {
+        println!("run-ahead failed to roll back state: {}", ret);
+    }
+    emulated
+}
+
+fn mame_enqueue_input_event(mame: *mut mame_t, event: &EmuInputEvent) {
+    let mame_input = mame_input_event_t {
+        key: event.value,
+        type_: match event.kind {
+            InputKind::INPUT_KEY_UP => mame_input_enum_t_INPUT_KEY_UP,
+            InputKind::INPUT_KEY_DOWN => mame_input_enum_t_INPUT_KEY_DOWN,
+            InputKind::INPUT_BUTTON_UP => mame_input_enum_t_INPUT_BUTTON_UP,
+            InputKind::INPUT_BUTTON_DOWN => mame_input_enum_t_INPUT_BUTTON_DOWN,
+            InputKind::INPUT_AXIS_ABSOLUTE => mame_input_enum_t_INPUT_AXIS_ABSOLUTE,
+            InputKind::INPUT_AXIS_RELATIVE => mame_input_enum_t_INPUT_AXIS_RELATIVE,
+            InputKind::INPUT_POINTER_MOVE => mame_input_enum_t_INPUT_POINTER_MOVE,
+        },
+        player: event.player,
+        value: event.analog,
+        x: event.x,
+        y: event.y,
+    };
+    unsafe {
+        mame_fn!(mame, enqueue_input_event)(mame_input)
+    }
+}
+
+fn mame_address_space(space: AddressSpace) -> mame_address_space_t {
+    match space {
+        AddressSpace::ADDRESS_SPACE_PROGRAM => mame_address_space_t_ADDRESS_SPACE_PROGRAM,
+        AddressSpace::ADDRESS_SPACE_DATA => mame_address_space_t_ADDRESS_SPACE_DATA,
+        AddressSpace::ADDRESS_SPACE_IO => mame_address_space_t_ADDRESS_SPACE_IO,
+        AddressSpace::ADDRESS_SPACE_OPCODES => mame_address_space_t_ADDRESS_SPACE_OPCODES,
+    }
+}
+
+// audit_cb is only called until audit_system returns, so the callback can live on the stack
+fn mame_audit_system<F>(mame: *mut mame_t, system_name: &CString, callback: &mut F) -> i32
+where F: FnMut(mame_rom_audit_record_t), {
+    unsafe {
+        mame_fn!(mame, audit_system)(
+            system_name.as_ptr(),
+            callback as *mut F as *mut c_void,
+            Some(mame_rom_audit_cb_closure::<F>))
+    }
+}
+
+unsafe extern "C" fn mame_rom_audit_cb_closure<F>(ctx: *mut libc::c_void, record: mame_rom_audit_record_t)
+where F: FnMut(mame_rom_audit_record_t), {
+    let callback_ptr = ctx as *mut F;
+    let callback = &mut *callback_ptr;
+    callback(record);
+}
+
+// names coming from commands may hold anything, but a NUL can't be passed on to mame
+fn mame_cstring(s: &str) -> Result<CString, String> {
+    CString::new(s).map_err(|_| format!("{:?} contains a NUL character", s))
+}
+
+fn mame_string(s: *const c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(s).to_string_lossy().into_owned() }
+    }
+}
+
+unsafe extern "C" fn mame_frame_begin_cb_closure<F>(ctx: *mut libc::c_void, frame_number: u64)
+where F: FnMut(u64), {
+    let callback_ptr = ctx as *mut F;
+    let callback = &mut *callback_ptr;
+    callback(frame_number);
 }
 
 unsafe extern "C" fn mame_image_cb_closure<F>(ctx: *mut libc::c_void, frame: mame_image_frame_t)
@@ -122,92 +609,460 @@ where F: FnMut(mame_sound_frame_t), {
 
 impl Emulator for MameEmulator {
     fn set_image_frame_info(&mut self, w: usize, h: usize, fps: usize) {
-        self.fps = fps;
+        self.pacer.lock().unwrap().set_fps(fps);
         unsafe {
-            match (*self.mame_inst).set_image_frame_info {
-                Some(f) => f(w as i32, h as i32),
-                None => panic!("set_frame_info method is not implemented.")
-            }
+            mame_fn!(self.mame_inst, set_image_frame_info)(w as i32, h as i32)
         }
     }
 
-    fn set_image_frame_cb(&mut self, mut callback: impl FnMut(EmuImageFrame)) {
+    fn set_image_frame_cb(&mut self, mut callback: impl FnMut(EmuImageFrame) + Send + 'static) -> EmuCallbackGuard {
+        let pacer = self.pacer.clone();
+        let discontinuity = self.discontinuity.clone();
+        let inputs = self.inputs.clone();
+        let rewinder = self.rewinder.clone();
+        let run_ahead = self.run_ahead.clone();
+        let image_pool = self.image_pool.clone();
         mame_register_frame_cb(
             self.mame_inst,
+            self.image_cb_registration.clone(),
             move |raw_frame: mame_image_frame_t| {
-                if self.should_update_image_frame() {
+                // turbo rates are in presses per second of the emulated system
+                inputs.lock().unwrap().macros().set_refresh_rate(raw_frame.refresh_rate);
+                rewinder.lock().unwrap().set_refresh_rate(raw_frame.refresh_rate);
+                // an image from ahead stands in for the real frame it is shown at
+                let frame_number = if run_ahead.speculating.load(Ordering::SeqCst) {
+                    run_ahead.frame_number.load(Ordering::SeqCst)
+                } else if run_ahead.hide_real_frame.load(Ordering::SeqCst) {
+                    return;
+                } else {
+                    raw_frame.frame_number
+                };
+                let picked = pacer.lock().unwrap().pick(frame_number, raw_frame.refresh_rate);
+                if let Some(emu_timestamp) = picked {
                     let buf = unsafe { slice::from_raw_parts(raw_frame.buffer, raw_frame.buf_size as usize) };
                     callback(EmuImageFrame {
-                        buf: Vec::from(buf),
+                        buf: image_pool.acquire_from(buf),
+                        width: raw_frame.width as usize,
+                        height: raw_frame.height as usize,
+                        stride: raw_frame.stride as usize,
+                        format: match raw_frame.format {
+                            mame_pixel_format_t_PIXEL_FORMAT_RGBA32 => PixelFormat::PIXEL_FORMAT_RGBA32,
+                            _ => PixelFormat::PIXEL_FORMAT_BGRA32,
+                        },
+                        frame_number: frame_number,
                         timestamp: utils::time::now_utc(),
+                        emu_timestamp: emu_timestamp,
+                        discontinuity: discontinuity.swap(false, Ordering::SeqCst),
                     });
                 }
             }
-        );
+        )
     }
 
-    fn set_sound_frame_cb(&mut self, mut callback: impl FnMut(EmuSoundFrame)) {
+    fn set_sound_frame_cb(&mut self, mut callback: impl FnMut(EmuSoundFrame) + Send + 'static) -> EmuCallbackGuard {
+        let pacer = self.pacer.clone();
+        let run_ahead = self.run_ahead.clone();
+        let sound_pool = self.sound_pool.clone();
+        let mut sound_clock = utils::pacing::SoundClock::new();
         mame_register_sound_cb(
             self.mame_inst,
+            self.sound_cb_registration.clone(),
             move |raw_frame: mame_sound_frame_t| {
-                let samples = raw_frame.samples as usize;
+                // emulate_frame is told not to emit sound from ahead, this is in case it does anyway
+                if run_ahead.speculating.load(Ordering::SeqCst) {
+                    return;
+                }
                 let channels = raw_frame.channels as usize;
+                if channels == 0 {
+                    println!("dropping a sound frame of {} samples without channels", raw_frame.samples);
+                    return;
+                }
+                let timing = {
+                    let pacer = pacer.lock().unwrap();
+                    if !pacer.is_realtime() {
+                        let silence = sound_clock.place_silence(&pacer, raw_frame.sample_rate as usize);
+                        drop(pacer);
+                        if let Some((emu_timestamp, samples)) = silence {
+                            callback(EmuSoundFrame {
+                                samples: samples,
+                                buf: utils::pacing::silence(&sound_pool, samples, channels),
+                                sample_rate: raw_frame.sample_rate as usize,
+                                channels: channels,
+                                timestamp: utils::time::now_utc(),
+                                emu_timestamp: emu_timestamp,
+                            });
+                        }
+                        return;
+                    }
+                    sound_clock.place(&pacer, raw_frame.samples as usize, raw_frame.sample_rate as usize)
+                };
+
                 let buf = unsafe {
-                    let total_samples = samples * channels;
+                    let total_samples = raw_frame.samples as usize * channels;
                     slice::from_raw_parts(raw_frame.buffer, total_samples)
                 };
+                let buf = utils::pacing::correct_samples(&sound_pool, buf, channels, timing.correction);
                 callback(EmuSoundFrame {
-                    buf: Vec::from(buf),
+                    samples: buf.len() / channels,
+                    buf: buf,
                     sample_rate: raw_frame.sample_rate as usize,
-                    samples: samples,
                     channels: channels,
                     timestamp: utils::time::now_utc(),
+                    emu_timestamp: timing.emu_timestamp,
                 });
             }
-        );
+        )
     }
 
-    fn put_input_event(&self, event: EmuInputEvent) {
-        let mame_input = mame_input_event_t {
-            key: event.value,
-            type_: match event.kind {
-                InputKind::INPUT_KEY_UP => mame_input_enum_t_INPUT_KEY_UP,
-                InputKind::INPUT_KEY_DOWN => mame_input_enum_t_INPUT_KEY_DOWN,
-            }
-        };
-        unsafe {
-            match (*self.mame_inst).enqueue_input_event {
-                Some(f) => f(mame_input),
-                None => panic!("enqueue_input_event is not implemented.")
+    fn set_options(&mut self, options: &EmulatorOptions) -> Result<(), String> {
+        let run_ahead_frames = options.run_ahead.unwrap_or(0);
+        if run_ahead_frames > MAX_RUN_AHEAD_FRAMES {
+            return Err(format!("run_ahead {} is out of range [0, {}]", run_ahead_frames, MAX_RUN_AHEAD_FRAMES));
+        }
+
+        for (name, value) in options.to_mame_options() {
+            let c_name = mame_cstring(&name)?;
+            let c_value = mame_cstring(&value)?;
+            let ret = unsafe {
+                mame_fn!(self.mame_inst, set_option)(c_name.as_ptr(), c_value.as_ptr())
+            };
+            if ret != 0 {
+                return Err(format!("failed to set option {} to {}: {}", name, value, ret));
             }
         }
+        // off unless set for the system
+        self.run_ahead.frames.store(run_ahead_frames, Ordering::SeqCst);
+        Ok(())
     }
 
-    fn run(&self, system_name: &str) -> i32 {
-        let sys_name = CString::new(system_name).unwrap();
-        unsafe {
-            match (*self.mame_inst).run {
-                Some(f) => f(sys_name.as_ptr() as *const c_char),
-                None => panic!("run method is not implemented.")
+    fn put_input_event(&self, event: EmuInputEvent) {
+        self.inputs.lock().unwrap().put(event);
+    }
+
+    fn set_input_mode(&self, mode: InputMode) {
+        self.inputs.lock().unwrap().set_mode(mode);
+    }
+
+    fn set_turbo(&self, player: u8, button: u8, rate: f64) -> Result<(), String> {
+        self.inputs.lock().unwrap().macros().set_turbo(player, button, rate)
+    }
+
+    fn set_macro(&self, player: u8, name: &str, steps: Vec<MacroStep>) {
+        self.inputs.lock().unwrap().macros().set_macro(player, name, steps);
+    }
+
+    fn run_macro(&self, player: u8, name: &str) -> Result<(), String> {
+        self.inputs.lock().unwrap().macros().run_macro(player, name)
+    }
+
+    fn version(&self) -> String {
+        mame_string(unsafe { mame_fn!(self.mame_inst, get_version)() })
+    }
+
+    fn run(&self, system_name: &str) -> EmuRunHandle {
+        let sys_name = mame_cstring(system_name);
+        let emu = self.clone();
+        let stopper = self.clone();
+        self.lifecycle.spawn(
+            move || stopper.stop(),
+            move || {
+                // reported as the exit of the run, like any other failure to start the system
+                let sys_name = match sys_name {
+                    Ok(sys_name) => sys_name,
+                    Err(msg) => {
+                        println!("{}", msg);
+                        return 1;
+                    },
+                };
+                let mame_inst = emu.mame_inst;
+                let inputs = emu.inputs.clone();
+                let rewinder = emu.rewinder.clone();
+                let run_ahead = emu.run_ahead.clone();
+                let mut state_buf = vec![];
+                // a state queued for a previous run doesn't belong to this one
+                emu.pending_load.lock().unwrap().take();
+                mame_run_with_frame_begin_cb(mame_inst, &sys_name, |frame_number| {
+                    emu.apply_pending_load();
+                    inputs.lock().unwrap().begin_frame(frame_number, |event| {
+                        mame_enqueue_input_event(mame_inst, event);
+                    });
+                    rewinder.lock().unwrap().frame_begin(|| emu.save_state_data());
+                    let hide_real_frame = mame_run_ahead(mame_inst, &run_ahead, frame_number, &mut state_buf);
+                    run_ahead.hide_real_frame.store(hide_real_frame, Ordering::SeqCst);
+                })
             }
+        )
+    }
+
+    fn stop(&self) {
+        self.lifecycle.request_stop();
+        unsafe {
+            mame_fn!(self.mame_inst, stop)()
         }
     }
 
     fn pause(&self) {
         unsafe {
-            match (*self.mame_inst).pause {
-                Some(f) => f(),
-                None => panic!("pause method is not implemented.")
-            }
+            mame_fn!(self.mame_inst, pause)()
         }
+        self.lifecycle.set_paused(true);
     }
 
     fn resume(&self) {
         unsafe {
-            match (*self.mame_inst).resume {
-                Some(f) => f(),
-                None => panic!("resume method is not implemented.")
-            }
+            mame_fn!(self.mame_inst, resume)()
+        }
+        self.lifecycle.set_paused(false);
+    }
+
+    fn set_speed(&self, speed: f64) -> Result<(), String> {
+        validate_speed(speed)?;
+        self.pacer.lock().unwrap().set_speed(speed);
+        unsafe {
+            mame_fn!(self.mame_inst, set_speed)(speed)
+        }
+        Ok(())
+    }
+
+    fn set_throttled(&self, throttled: bool) {
+        self.pacer.lock().unwrap().set_throttled(throttled);
+        unsafe {
+            mame_fn!(self.mame_inst, set_throttled)(throttled as c_int)
+        }
+    }
+
+    fn step_frame(&self) -> Result<(), String> {
+        if !self.lifecycle.is_paused() {
+            return Err(format!("frames can only be stepped while paused"));
+        }
+
+        self.pacer.lock().unwrap().force_next();
+        unsafe {
+            mame_fn!(self.mame_inst, step_frame)()
+        }
+        Ok(())
+    }
+
+    fn reset(&self, kind: ResetKind) {
+        let reset_type = match kind {
+            ResetKind::RESET_SOFT => mame_reset_type_t_RESET_SOFT,
+            ResetKind::RESET_HARD => mame_reset_type_t_RESET_HARD,
+        };
+        self.discontinuity.store(true, Ordering::SeqCst);
+        unsafe {
+            mame_fn!(self.mame_inst, reset)(reset_type)
         }
+        self.lifecycle.notify(EmuEvent::Reset(kind));
+    }
+
+    fn load_system(&self, system_name: &str) -> Result<(), String> {
+        let sys_name = mame_cstring(system_name)?;
+        let ret = unsafe {
+            mame_fn!(self.mame_inst, load_system)(sys_name.as_ptr())
+        };
+        match ret {
+            0 => {
+                self.rewinder.lock().unwrap().clear();
+                self.discontinuity.store(true, Ordering::SeqCst);
+                self.lifecycle.notify(EmuEvent::SystemLoaded(String::from(system_name)));
+                Ok(())
+            },
+            _ => Err(format!("failed to load {}: {}", system_name, ret)),
+        }
+    }
+
+    fn set_rewind(&self, settings: Option<RewindSettings>) -> Result<(), String> {
+        self.rewinder.lock().unwrap().configure(settings)
+    }
+
+    fn rewind(&self, seconds: f64) -> Result<(), String> {
+        let data = self.rewinder.lock().unwrap().rewind(seconds)?;
+        self.queue_state_load(data)
+    }
+
+    fn save_state(&self, slot_name: &str) -> Result<(), String> {
+        validate_slot_name(slot_name)?;
+        let slot = mame_cstring(slot_name)?;
+        let ret = unsafe {
+            mame_fn!(self.mame_inst, save_state)(slot.as_ptr() as *const c_char)
+        };
+        match ret {
+            0 => Ok(()),
+            _ => Err(format!("failed to save state to slot {}: {}", slot_name, ret)),
+        }
+    }
+
+    fn load_state(&self, slot_name: &str) -> Result<(), String> {
+        validate_slot_name(slot_name)?;
+        let slot = mame_cstring(slot_name)?;
+        let ret = unsafe {
+            mame_fn!(self.mame_inst, load_state)(slot.as_ptr() as *const c_char)
+        };
+        match ret {
+            0 => {
+                self.run_ahead.loads.fetch_add(1, Ordering::SeqCst);
+                self.discontinuity.store(true, Ordering::SeqCst);
+                Ok(())
+            },
+            _ => Err(format!("failed to load state from slot {}: {}", slot_name, ret)),
+        }
+    }
+
+    fn save_state_data(&self) -> Result<Vec<u8>, String> {
+        let state_size = unsafe {
+            mame_fn!(self.mame_inst, get_state_size)()
+        };
+        if state_size == 0 {
+            return Err(format!("state is not available for the running system"));
+        }
+
+        let mut data = vec![0u8; state_size];
+        let ret = unsafe {
+            mame_fn!(self.mame_inst, save_state_to_buffer)(data.as_mut_ptr(), data.len())
+        };
+        match ret {
+            0 => Ok(data),
+            _ => Err(format!("failed to save state to buffer: {}", ret)),
+        }
+    }
+
+    // the state is loaded at the beginning of the next frame, which is a stepped one while paused
+    fn load_state_data(&self, data: &[u8]) -> Result<(), String> {
+        self.queue_state_load(data.to_vec())
+    }
+
+    fn read_memory(&self, location: &MemoryLocation) -> Result<u64, String> {
+        location.validate()?;
+        let cpu_tag = mame_cstring(&location.cpu_tag)?;
+        let mut value: u64 = 0;
+        let ret = unsafe {
+            mame_fn!(self.mame_inst, read_memory)(
+                cpu_tag.as_ptr(), mame_address_space(location.space),
+                location.address, location.width as c_int, &mut value)
+        };
+        match ret {
+            0 => Ok(value),
+            _ => Err(format!("failed to read memory at {:?}: {}", location, ret)),
+        }
+    }
+
+    fn write_memory(&self, location: &MemoryLocation, value: u64) -> Result<(), String> {
+        location.validate()?;
+        let cpu_tag = mame_cstring(&location.cpu_tag)?;
+        let ret = unsafe {
+            mame_fn!(self.mame_inst, write_memory)(
+                cpu_tag.as_ptr(), mame_address_space(location.space),
+                location.address, location.width as c_int, value)
+        };
+        match ret {
+            0 => Ok(()),
+            _ => Err(format!("failed to write memory at {:?}: {}", location, ret)),
+        }
+    }
+
+    fn audit_system(&self, system_name: &str) -> Result<RomAudit, String> {
+        let sys_name = mame_cstring(system_name)?;
+        let mut records = vec![];
+        let ret = {
+            let mut callback = |record: mame_rom_audit_record_t| {
+                records.push(RomAuditRecord {
+                    name: mame_string(record.name),
+                    status: match record.status {
+                        mame_rom_status_t_ROM_STATUS_GOOD => RomStatus::ROM_STATUS_GOOD,
+                        mame_rom_status_t_ROM_STATUS_NOT_FOUND => RomStatus::ROM_STATUS_NOT_FOUND,
+                        mame_rom_status_t_ROM_STATUS_BAD_SIZE => RomStatus::ROM_STATUS_BAD_SIZE,
+                        mame_rom_status_t_ROM_STATUS_NO_DUMP => RomStatus::ROM_STATUS_NO_DUMP,
+                        _ => RomStatus::ROM_STATUS_BAD_CHECKSUM,
+                    },
+                    expected_size: record.expected_size,
+                    actual_size: record.actual_size,
+                    optional: record.optional != 0,
+                });
+            };
+            mame_audit_system(self.mame_inst, &sys_name, &mut callback)
+        };
+        match ret {
+            0 => Ok(RomAudit {
+                system_name: String::from(system_name),
+                records: records,
+            }),
+            _ => Err(format!("failed to audit {}: {}", system_name, ret)),
+        }
+    }
+
+    fn system_info(&self, system_name: &str) -> Result<SystemInfo, String> {
+        let sys_name = mame_cstring(system_name)?;
+        let mut info: mame_system_info_t = unsafe { mem::zeroed() };
+        let ret = unsafe {
+            mame_fn!(self.mame_inst, get_system_info)(sys_name.as_ptr(), &mut info)
+        };
+        if ret != 0 {
+            return Err(format!("failed to get system info of {}: {}", system_name, ret));
+        }
+
+        Ok(SystemInfo {
+            system_name: String::from(system_name),
+            description: mame_string(info.description),
+            year: mame_string(info.year),
+            manufacturer: mame_string(info.manufacturer),
+            screen_width: info.screen_width as usize,
+            screen_height: info.screen_height as usize,
+            orientation: match info.orientation {
+                mame_orientation_t_ORIENTATION_ROT90 => Orientation::ORIENTATION_ROT90,
+                mame_orientation_t_ORIENTATION_ROT180 => Orientation::ORIENTATION_ROT180,
+                mame_orientation_t_ORIENTATION_ROT270 => Orientation::ORIENTATION_ROT270,
+                _ => Orientation::ORIENTATION_ROT0,
+            },
+            refresh_rate: info.refresh_rate,
+            players: info.players as usize,
+            buttons: info.buttons as usize,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn noop() {}
+
+    #[test]
+    fn every_method_is_missing_from_an_empty_instance() {
+        let mame: mame_t = unsafe { mem::zeroed() };
+        let missing = mame_missing_methods(&mame);
+        assert!(missing.contains(&"run"));
+        assert!(missing.contains(&"step_frame"));
+        assert!(missing.contains(&"emulate_frame"));
+    }
+
+    #[test]
+    fn implemented_methods_are_not_missing() {
+        let mut mame: mame_t = unsafe { mem::zeroed() };
+        mame.step_frame = Some(noop);
+        mame.stop = Some(noop);
+        let missing = mame_missing_methods(&mame);
+        assert!(!missing.contains(&"step_frame"));
+        assert!(!missing.contains(&"stop"));
+        assert!(missing.contains(&"run"));
+    }
+
+    #[test]
+    fn slot_names_stay_in_the_state_directory() {
+        assert!(validate_slot_name("slot1").is_ok());
+        assert!(validate_slot_name("").is_err());
+        assert!(validate_slot_name("../slot1").is_err());
+        assert!(validate_slot_name("a/b").is_err());
+        assert!(validate_slot_name("a\0b").is_err());
+    }
+
+    #[test]
+    fn names_with_a_nul_are_an_error() {
+        assert_eq!(mame_cstring("pacman").unwrap().as_bytes(), b"pacman");
+        assert!(mame_cstring("pac\0man").is_err());
+    }
+
+    #[test]
+    fn create_fails_without_libmame() {
+        assert!(MameEmulator::create("./no/such/libmame.so", 320, 240, 30).is_err());
     }
 }
@@ -0,0 +1,1090 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc::{c_char, c_uint, c_void};
+
+use libbuf::BufferPool;
+
+use crate::{Emulator, EmuCallbackGuard, EmuImageFrame, EmuRunHandle, EmuSoundFrame, EmuInputEvent};
+use crate::{AddressSpace, EmuEvent, InputKind, MemoryLocation, PixelFormat, ResetKind, INPUT_AXIS_MAX};
+use crate::{EmulatorOptions, MacroStep, Orientation, RewindSettings, RomAudit, RomAuditRecord, RomStatus, SystemInfo};
+use crate::lifecycle::Lifecycle;
+use crate::replay::{InputMode, InputScheduler};
+use crate::rewind::Rewinder;
+use crate::utils;
+use crate::{FRAME_POOL_SIZE, validate_slot_name, validate_speed};
+
+const BYTES_PER_PIXEL: usize = 4;
+const CHANNELS: usize = 2;
+
+// Every player has a retropad, whose buttons are numbered as the RETRO_DEVICE_ID_JOYPAD_*
+// ids (B, Y, SELECT, START, UP, DOWN, LEFT, RIGHT, A, X, L, R, L2, R2, L3, R3), and the
+// axes of its left and right analog sticks (left x, left y, right x, right y).
+const PLAYERS: usize = 4;
+const JOYPAD_BUTTONS: usize = 16;
+const ANALOG_AXES: usize = 4;
+
+const DEFAULT_STATE_DIRECTORY: &str = "./sta";
+
+// from libretro.h
+const RETRO_API_VERSION: c_uint = 1;
+
+const RETRO_DEVICE_MASK: c_uint = 0xff;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_DEVICE_ANALOG: c_uint = 5;
+const RETRO_DEVICE_ID_JOYPAD_MASK: c_uint = 256;
+
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+
+const RETRO_ENVIRONMENT_GET_CAN_DUPE: c_uint = 3;
+const RETRO_ENVIRONMENT_SHUTDOWN: c_uint = 7;
+const RETRO_ENVIRONMENT_SET_PERFORMANCE_LEVEL: c_uint = 8;
+const RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY: c_uint = 9;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_SET_INPUT_DESCRIPTORS: c_uint = 11;
+const RETRO_ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+const RETRO_ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+const RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+const RETRO_ENVIRONMENT_SET_SUPPORT_NO_GAME: c_uint = 18;
+const RETRO_ENVIRONMENT_GET_SAVE_DIRECTORY: c_uint = 31;
+const RETRO_ENVIRONMENT_SET_SYSTEM_AV_INFO: c_uint = 32;
+const RETRO_ENVIRONMENT_SET_GEOMETRY: c_uint = 37;
+
+const RETRO_PIXEL_FORMAT_0RGB1555: c_uint = 0;
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const RETRO_PIXEL_FORMAT_RGB565: c_uint = 2;
+
+#[repr(C)]
+struct retro_system_info {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct retro_game_geometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct retro_system_timing {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct retro_system_av_info {
+    geometry: retro_game_geometry,
+    timing: retro_system_timing,
+}
+
+#[repr(C)]
+struct retro_game_info {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct retro_variable {
+    key: *const c_char,
+    value: *const c_char,
+}
+
+type RetroEnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type RetroVideoRefreshFn = unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type RetroAudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type RetroAudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type RetroInputPollFn = unsafe extern "C" fn();
+type RetroInputStateFn = unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+// Entry points of a libretro core, looked up when the core is loaded
+struct RetroCore {
+    set_environment: unsafe extern "C" fn(RetroEnvironmentFn),
+    set_video_refresh: unsafe extern "C" fn(RetroVideoRefreshFn),
+    set_audio_sample: unsafe extern "C" fn(RetroAudioSampleFn),
+    set_audio_sample_batch: unsafe extern "C" fn(RetroAudioSampleBatchFn),
+    set_input_poll: unsafe extern "C" fn(RetroInputPollFn),
+    set_input_state: unsafe extern "C" fn(RetroInputStateFn),
+    init: unsafe extern "C" fn(),
+    api_version: unsafe extern "C" fn() -> c_uint,
+    get_system_info: unsafe extern "C" fn(*mut retro_system_info),
+    get_system_av_info: unsafe extern "C" fn(*mut retro_system_av_info),
+    reset: unsafe extern "C" fn(),
+    run: unsafe extern "C" fn(),
+    serialize_size: unsafe extern "C" fn() -> usize,
+    serialize: unsafe extern "C" fn(*mut c_void, usize) -> bool,
+    unserialize: unsafe extern "C" fn(*const c_void, usize) -> bool,
+    load_game: unsafe extern "C" fn(*const retro_game_info) -> bool,
+    unload_game: unsafe extern "C" fn(),
+    get_memory_data: unsafe extern "C" fn(c_uint) -> *mut c_void,
+    get_memory_size: unsafe extern "C" fn(c_uint) -> usize,
+    library_name: String,
+    library_version: String,
+    valid_extensions: Vec<String>,
+    need_fullpath: bool,
+    // keeps the core loaded for as long as the entry points above are in use
+    _lib: libloading::Library,
+}
+
+macro_rules! retro_sym {
+    ($lib:expr, $core_path:expr, $name:expr) => {
+        *$lib.get(concat!($name, "\0").as_bytes())
+            .map_err(|e| format!("{} is not a libretro core: {}", $core_path, e))?
+    };
+}
+
+impl RetroCore {
+    fn load(core_path: &str) -> Result<RetroCore, String> {
+        let lib = libloading::Library::new(core_path)
+            .map_err(|e| format!("failed to load libretro core from {}: {}", core_path, e))?;
+        let mut core = unsafe {
+            RetroCore {
+                set_environment: retro_sym!(lib, core_path, "retro_set_environment"),
+                set_video_refresh: retro_sym!(lib, core_path, "retro_set_video_refresh"),
+                set_audio_sample: retro_sym!(lib, core_path, "retro_set_audio_sample"),
+                set_audio_sample_batch: retro_sym!(lib, core_path, "retro_set_audio_sample_batch"),
+                set_input_poll: retro_sym!(lib, core_path, "retro_set_input_poll"),
+                set_input_state: retro_sym!(lib, core_path, "retro_set_input_state"),
+                init: retro_sym!(lib, core_path, "retro_init"),
+                api_version: retro_sym!(lib, core_path, "retro_api_version"),
+                get_system_info: retro_sym!(lib, core_path, "retro_get_system_info"),
+                get_system_av_info: retro_sym!(lib, core_path, "retro_get_system_av_info"),
+                reset: retro_sym!(lib, core_path, "retro_reset"),
+                run: retro_sym!(lib, core_path, "retro_run"),
+                serialize_size: retro_sym!(lib, core_path, "retro_serialize_size"),
+                serialize: retro_sym!(lib, core_path, "retro_serialize"),
+                unserialize: retro_sym!(lib, core_path, "retro_unserialize"),
+                load_game: retro_sym!(lib, core_path, "retro_load_game"),
+                unload_game: retro_sym!(lib, core_path, "retro_unload_game"),
+                get_memory_data: retro_sym!(lib, core_path, "retro_get_memory_data"),
+                get_memory_size: retro_sym!(lib, core_path, "retro_get_memory_size"),
+                library_name: String::new(),
+                library_version: String::new(),
+                valid_extensions: vec![],
+                need_fullpath: false,
+                _lib: lib,
+            }
+        };
+
+        let api_version = unsafe { (core.api_version)() };
+        if api_version != RETRO_API_VERSION {
+            return Err(format!(
+                "{} implements libretro api {}, only {} is supported", core_path, api_version, RETRO_API_VERSION));
+        }
+
+        let mut info: retro_system_info = unsafe { mem::zeroed() };
+        unsafe { (core.get_system_info)(&mut info) };
+        core.library_name = retro_string(info.library_name);
+        core.library_version = retro_string(info.library_version);
+        core.valid_extensions = retro_string(info.valid_extensions)
+            .split('|')
+            .filter(|ext| !ext.is_empty())
+            .map(String::from)
+            .collect();
+        core.need_fullpath = info.need_fullpath;
+        Ok(core)
+    }
+}
+
+fn retro_string(s: *const c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(s).to_string_lossy().into_owned() }
+    }
+}
+
+// callbacks are tagged with a registration id so that a stale guard doesn't clear a newer one
+type ImageFrameCb = (u64, Box<dyn FnMut(EmuImageFrame) + Send>);
+type SoundFrameCb = (u64, Box<dyn FnMut(EmuSoundFrame) + Send>);
+
+// reset and content switches are carried out on the emulation thread, between two frames
+enum RetroRequest {
+    Reset(ResetKind),
+    Load(String),
+}
+
+struct RetroState {
+    w: usize,
+    h: usize,
+    speed: f64,
+    throttled: bool,
+    step_pending: bool,
+    frame_no: u64,
+    discontinuity: bool,
+    pixel_format: c_uint,
+    av_info: retro_system_av_info,
+    joypad: [u16; PLAYERS],
+    analog: [[i16; ANALOG_AXES]; PLAYERS],
+    audio: Vec<i16>,
+    content: Option<String>,
+    pending: Option<RetroRequest>,
+    shutdown_requested: bool,
+    rompath: Vec<String>,
+    system_directory: CString,
+    save_directory: CString,
+    state_directory: String,
+    variables: HashMap<String, CString>,
+    variables_updated: bool,
+    cb_registration: u64,
+}
+
+// Everything the callbacks of the core get to, which carry no context pointer
+struct RetroContext {
+    core: RetroCore,
+    // the core isn't thread safe, calls into it are made one at a time
+    core_lock: Mutex<()>,
+    state: Mutex<RetroState>,
+    pacer: Mutex<utils::pacing::FramePacer>,
+    sound_clock: Mutex<utils::pacing::SoundClock>,
+    image_frame_cb: Mutex<Option<ImageFrameCb>>,
+    sound_frame_cb: Mutex<Option<SoundFrameCb>>,
+    // the last picture handed out, which stands in for the frames the core duplicates
+    last_image: Mutex<Vec<u8>>,
+    image_pool: BufferPool<u8>,
+    sound_pool: BufferPool<i16>,
+}
+
+// A libretro core is a singleton within the process, so is its context
+static RETRO_CONTEXT: AtomicPtr<RetroContext> = AtomicPtr::new(ptr::null_mut());
+
+// the core only calls back once the context is in place
+fn retro_context() -> &'static RetroContext {
+    unsafe { &*RETRO_CONTEXT.load(Ordering::SeqCst) }
+}
+
+unsafe extern "C" fn retro_environment_cb(cmd: c_uint, data: *mut c_void) -> bool {
+    retro_context().environment(cmd, data)
+}
+
+unsafe extern "C" fn retro_video_refresh_cb(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    retro_context().video_refresh(data as *const u8, width as usize, height as usize, pitch);
+}
+
+unsafe extern "C" fn retro_audio_sample_cb(left: i16, right: i16) {
+    retro_context().state.lock().unwrap().audio.extend_from_slice(&[left, right]);
+}
+
+unsafe extern "C" fn retro_audio_sample_batch_cb(data: *const i16, frames: usize) -> usize {
+    let samples = slice::from_raw_parts(data, frames * CHANNELS);
+    retro_context().state.lock().unwrap().audio.extend_from_slice(samples);
+    frames
+}
+
+// inputs are applied at the beginning of the frame, there is nothing left to poll
+unsafe extern "C" fn retro_input_poll_cb() {}
+
+unsafe extern "C" fn retro_input_state_cb(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16 {
+    retro_context().input_state(port as usize, device, index as usize, id)
+}
+
+impl RetroContext {
+    unsafe fn environment(&self, cmd: c_uint, data: *mut c_void) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match cmd {
+            RETRO_ENVIRONMENT_GET_CAN_DUPE => {
+                *(data as *mut bool) = true;
+                true
+            },
+            RETRO_ENVIRONMENT_SHUTDOWN => {
+                state.shutdown_requested = true;
+                true
+            },
+            // accepted, there is nothing to do about them
+            RETRO_ENVIRONMENT_SET_PERFORMANCE_LEVEL |
+            RETRO_ENVIRONMENT_SET_INPUT_DESCRIPTORS |
+            RETRO_ENVIRONMENT_SET_VARIABLES |
+            RETRO_ENVIRONMENT_SET_SUPPORT_NO_GAME => true,
+            RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY => {
+                *(data as *mut *const c_char) = state.system_directory.as_ptr();
+                true
+            },
+            RETRO_ENVIRONMENT_GET_SAVE_DIRECTORY => {
+                *(data as *mut *const c_char) = state.save_directory.as_ptr();
+                true
+            },
+            RETRO_ENVIRONMENT_SET_PIXEL_FORMAT => {
+                let pixel_format = *(data as *const c_uint);
+                match pixel_format {
+                    RETRO_PIXEL_FORMAT_0RGB1555 | RETRO_PIXEL_FORMAT_XRGB8888 | RETRO_PIXEL_FORMAT_RGB565 => {
+                        state.pixel_format = pixel_format;
+                        true
+                    },
+                    _ => false,
+                }
+            },
+            // variables not set in the options are left at the defaults of the core
+            RETRO_ENVIRONMENT_GET_VARIABLE => {
+                let variable = &mut *(data as *mut retro_variable);
+                let key = retro_string(variable.key);
+                match state.variables.get(&key) {
+                    Some(value) => {
+                        variable.value = value.as_ptr();
+                        true
+                    },
+                    None => {
+                        variable.value = ptr::null();
+                        false
+                    },
+                }
+            },
+            RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE => {
+                *(data as *mut bool) = state.variables_updated;
+                state.variables_updated = false;
+                true
+            },
+            RETRO_ENVIRONMENT_SET_SYSTEM_AV_INFO => {
+                state.av_info = *(data as *const retro_system_av_info);
+                state.discontinuity = true;
+                true
+            },
+            RETRO_ENVIRONMENT_SET_GEOMETRY => {
+                state.av_info.geometry = *(data as *const retro_game_geometry);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // data is null when the core repeats the previous frame
+    unsafe fn video_refresh(&self, data: *const u8, width: usize, height: usize, pitch: usize) {
+        let (frame_no, refresh_rate, pixel_format, w, h) = {
+            let state = self.state.lock().unwrap();
+            (state.frame_no, state.av_info.timing.fps, state.pixel_format, state.w, state.h)
+        };
+        let emu_timestamp = match self.pacer.lock().unwrap().pick(frame_no, refresh_rate) {
+            Some(emu_timestamp) => emu_timestamp,
+            None => return,
+        };
+
+        let mut image_frame_cb = self.image_frame_cb.lock().unwrap();
+        let cb = match image_frame_cb.as_mut() {
+            Some((_, cb)) => cb,
+            None => return,
+        };
+
+        let mut last_image = self.last_image.lock().unwrap();
+        if !data.is_null() && width > 0 && height > 0 {
+            let src_bytes_per_pixel = if pixel_format == RETRO_PIXEL_FORMAT_XRGB8888 { 4 } else { 2 };
+            let src = slice::from_raw_parts(data, pitch * (height - 1) + width * src_bytes_per_pixel);
+            last_image.resize(w * h * BYTES_PER_PIXEL, 0);
+            convert_image(src, width, height, pitch, pixel_format, &mut last_image, w, h);
+        } else if last_image.len() != w * h * BYTES_PER_PIXEL {
+            return;
+        }
+
+        let discontinuity = mem::replace(&mut self.state.lock().unwrap().discontinuity, false);
+        cb(EmuImageFrame {
+            buf: self.image_pool.acquire_from(&last_image),
+            width: w,
+            height: h,
+            stride: w * BYTES_PER_PIXEL,
+            format: PixelFormat::PIXEL_FORMAT_BGRA32,
+            frame_number: frame_no,
+            timestamp: utils::time::now_utc(),
+            emu_timestamp: emu_timestamp,
+            discontinuity: discontinuity,
+        });
+    }
+
+    fn input_state(&self, port: usize, device: c_uint, index: usize, id: c_uint) -> i16 {
+        let state = self.state.lock().unwrap();
+        if port >= PLAYERS {
+            return 0;
+        }
+        match device & RETRO_DEVICE_MASK {
+            RETRO_DEVICE_JOYPAD if id == RETRO_DEVICE_ID_JOYPAD_MASK => state.joypad[port] as i16,
+            RETRO_DEVICE_JOYPAD if (id as usize) < JOYPAD_BUTTONS => ((state.joypad[port] >> id) & 1) as i16,
+            RETRO_DEVICE_ANALOG => state.analog[port].get(index * 2 + id as usize).cloned().unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    // Passes the sound the core put out during the last frame on
+    fn flush_sound(&self) {
+        // the batch is handed back afterwards, so that its allocation is reused
+        let (mut audio, sample_rate) = {
+            let mut state = self.state.lock().unwrap();
+            let audio = mem::replace(&mut state.audio, vec![]);
+            (audio, state.av_info.timing.sample_rate.round() as usize)
+        };
+
+        // like mame, sound is only passed on at realtime speed, silence stands in for it otherwise
+        let frame = {
+            let pacer = self.pacer.lock().unwrap();
+            let mut sound_clock = self.sound_clock.lock().unwrap();
+            if !pacer.is_realtime() {
+                sound_clock.place_silence(&pacer, sample_rate).map(|(emu_timestamp, samples)| {
+                    (utils::pacing::silence(&self.sound_pool, samples, CHANNELS), emu_timestamp)
+                })
+            } else if audio.is_empty() {
+                None
+            } else {
+                let timing = sound_clock.place(&pacer, audio.len() / CHANNELS, sample_rate);
+                let buf = utils::pacing::correct_samples(&self.sound_pool, &audio, CHANNELS, timing.correction);
+                Some((buf, timing.emu_timestamp))
+            }
+        };
+
+        if let Some((_, cb)) = self.sound_frame_cb.lock().unwrap().as_mut() {
+            if let Some((buf, emu_timestamp)) = frame {
+                cb(EmuSoundFrame {
+                    samples: buf.len() / CHANNELS,
+                    buf: buf,
+                    sample_rate: sample_rate,
+                    channels: CHANNELS,
+                    timestamp: utils::time::now_utc(),
+                    emu_timestamp: emu_timestamp,
+                });
+            }
+        }
+        audio.clear();
+        self.state.lock().unwrap().audio = audio;
+    }
+}
+
+fn expand_5bits(v: u16) -> u8 {
+    ((v << 3) | (v >> 2)) as u8
+}
+
+fn expand_6bits(v: u16) -> u8 {
+    ((v << 2) | (v >> 4)) as u8
+}
+
+// Converts a picture of the core to BGRA32, scaled to w x h by nearest neighbour sampling
+fn convert_image(
+    src: &[u8], src_w: usize, src_h: usize, pitch: usize, pixel_format: c_uint, dst: &mut [u8], w: usize, h: usize) {
+
+    for y in 0..h {
+        let row = &src[(y * src_h / h) * pitch..];
+        for x in 0..w {
+            let sx = x * src_w / w;
+            let pixel = match pixel_format {
+                RETRO_PIXEL_FORMAT_XRGB8888 => {
+                    let p = u32::from_ne_bytes([row[sx * 4], row[sx * 4 + 1], row[sx * 4 + 2], row[sx * 4 + 3]]);
+                    [p as u8, (p >> 8) as u8, (p >> 16) as u8, 255]
+                },
+                RETRO_PIXEL_FORMAT_RGB565 => {
+                    let p = u16::from_ne_bytes([row[sx * 2], row[sx * 2 + 1]]);
+                    [expand_5bits(p & 0x1f), expand_6bits((p >> 5) & 0x3f), expand_5bits((p >> 11) & 0x1f), 255]
+                },
+                _ => {
+                    let p = u16::from_ne_bytes([row[sx * 2], row[sx * 2 + 1]]);
+                    [expand_5bits(p & 0x1f), expand_5bits((p >> 5) & 0x1f), expand_5bits((p >> 10) & 0x1f), 255]
+                },
+            };
+
+            let offset = (y * w + x) * BYTES_PER_PIXEL;
+            dst[offset..offset + BYTES_PER_PIXEL].copy_from_slice(&pixel);
+        }
+    }
+}
+
+// The content of a running game, which the core may refer to until it is unloaded
+struct RetroContent {
+    _path: CString,
+    _data: Vec<u8>,
+}
+
+// Runs a libretro core loaded at runtime. The system name is the content to run, either
+// a path or a file name without extension found in the rompath. The core gets a retropad
+// per player, see PLAYERS above for the button numbers.
+#[derive(Clone)]
+pub struct LibretroEmulator {
+    ctx: &'static RetroContext,
+    lifecycle: Lifecycle,
+    inputs: Arc<Mutex<InputScheduler>>,
+    rewinder: Arc<Mutex<Rewinder>>,
+}
+
+impl LibretroEmulator {
+    // The core stays loaded until the process exits, only one can be created.
+    pub fn create(core_path: &str, w: usize, h: usize, fps: usize) -> Result<impl Emulator, String> {
+        // the slot is taken before loading, so that concurrent calls can't both load a core
+        let reserved = ptr::NonNull::<RetroContext>::dangling().as_ptr();
+        if RETRO_CONTEXT.compare_exchange(ptr::null_mut(), reserved, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(format!("a libretro core is loaded already"));
+        }
+
+        let core = match RetroCore::load(core_path) {
+            Ok(core) => core,
+            Err(msg) => {
+                RETRO_CONTEXT.store(ptr::null_mut(), Ordering::SeqCst);
+                return Err(msg);
+            },
+        };
+        let ctx: &'static RetroContext = Box::leak(Box::new(RetroContext {
+            core: core,
+            core_lock: Mutex::new(()),
+            state: Mutex::new(RetroState {
+                w: w,
+                h: h,
+                speed: 1.0,
+                throttled: true,
+                step_pending: false,
+                frame_no: 0,
+                discontinuity: false,
+                pixel_format: RETRO_PIXEL_FORMAT_0RGB1555,
+                av_info: retro_system_av_info::default(),
+                joypad: [0; PLAYERS],
+                analog: [[0; ANALOG_AXES]; PLAYERS],
+                audio: vec![],
+                content: None,
+                pending: None,
+                shutdown_requested: false,
+                rompath: vec![String::from(".")],
+                system_directory: CString::new(".").unwrap(),
+                save_directory: CString::new(".").unwrap(),
+                state_directory: String::from(DEFAULT_STATE_DIRECTORY),
+                variables: HashMap::new(),
+                variables_updated: false,
+                cb_registration: 0,
+            }),
+            pacer: Mutex::new(utils::pacing::FramePacer::new(fps)),
+            sound_clock: Mutex::new(utils::pacing::SoundClock::new()),
+            image_frame_cb: Mutex::new(None),
+            sound_frame_cb: Mutex::new(None),
+            last_image: Mutex::new(vec![]),
+            image_pool: BufferPool::create(FRAME_POOL_SIZE),
+            sound_pool: BufferPool::create(FRAME_POOL_SIZE),
+        }));
+        RETRO_CONTEXT.store(ctx as *const RetroContext as *mut RetroContext, Ordering::SeqCst);
+
+        // the environment is set first, cores query it from retro_init on
+        unsafe {
+            (ctx.core.set_environment)(retro_environment_cb);
+            (ctx.core.init)();
+            (ctx.core.set_video_refresh)(retro_video_refresh_cb);
+            (ctx.core.set_audio_sample)(retro_audio_sample_cb);
+            (ctx.core.set_audio_sample_batch)(retro_audio_sample_batch_cb);
+            (ctx.core.set_input_poll)(retro_input_poll_cb);
+            (ctx.core.set_input_state)(retro_input_state_cb);
+        }
+
+        let lifecycle = Lifecycle::default();
+        let mut emu = LibretroEmulator {
+            ctx: ctx,
+            lifecycle: lifecycle.clone(),
+            inputs: Arc::new(Mutex::new(InputScheduler::new(lifecycle))),
+            rewinder: Arc::new(Mutex::new(Rewinder::new())),
+        };
+
+        emu.set_image_frame_info(w, h, fps);
+        Ok(emu)
+    }
+
+    fn next_cb_registration(&self) -> u64 {
+        let mut state = self.ctx.state.lock().unwrap();
+        state.cb_registration += 1;
+        state.cb_registration
+    }
+
+    fn apply_input(state: &mut RetroState, event: &EmuInputEvent) {
+        let player = event.player as usize;
+        let value = event.value as usize;
+        if player >= PLAYERS {
+            return;
+        }
+        match event.kind {
+            InputKind::INPUT_BUTTON_DOWN if value < JOYPAD_BUTTONS => state.joypad[player] |= 1 << value,
+            InputKind::INPUT_BUTTON_UP if value < JOYPAD_BUTTONS => state.joypad[player] &= !(1 << value),
+            InputKind::INPUT_AXIS_ABSOLUTE if value < ANALOG_AXES => {
+                state.analog[player][value] = (event.analog as i64 * i16::MAX as i64 / INPUT_AXIS_MAX as i64) as i16;
+            },
+            // keys, relative axes and pointers have no counterpart on a retropad
+            _ => {},
+        }
+    }
+
+    // system_name is either the path of the content or its file name without extension
+    fn find_content(&self, system_name: &str) -> Result<PathBuf, String> {
+        let path = Path::new(system_name);
+        if path.is_file() {
+            return Ok(path.to_path_buf());
+        }
+
+        let rompath = self.ctx.state.lock().unwrap().rompath.clone();
+        for dir in rompath.iter() {
+            for ext in self.ctx.core.valid_extensions.iter() {
+                let candidate = Path::new(dir).join(format!("{}.{}", system_name, ext));
+                if candidate.is_file() {
+                    return Ok(candidate);
+                }
+            }
+        }
+        Err(format!("no content for {} in {}", system_name, rompath.join(";")))
+    }
+
+    fn load_content(&self, system_name: &str) -> Result<RetroContent, String> {
+        let path = self.find_content(system_name)?;
+        let c_path = CString::new(path.to_string_lossy().as_bytes()).unwrap();
+        // cores which don't need the path are given the content in memory
+        let data = if self.ctx.core.need_fullpath {
+            vec![]
+        } else {
+            fs::read(&path).map_err(|e| format!("failed to read {:?}: {}", path, e))?
+        };
+        let game_info = retro_game_info {
+            path: c_path.as_ptr(),
+            data: if data.is_empty() { ptr::null() } else { data.as_ptr() as *const c_void },
+            size: data.len(),
+            meta: ptr::null(),
+        };
+
+        self.ctx.state.lock().unwrap().pixel_format = RETRO_PIXEL_FORMAT_0RGB1555;
+        let mut av_info = retro_system_av_info::default();
+        let loaded = {
+            let _core = self.ctx.core_lock.lock().unwrap();
+            unsafe {
+                let loaded = (self.ctx.core.load_game)(&game_info);
+                if loaded {
+                    (self.ctx.core.get_system_av_info)(&mut av_info);
+                }
+                loaded
+            }
+        };
+        if !loaded {
+            return Err(format!("{} failed to load {:?}", self.ctx.core.library_name, path));
+        }
+
+        {
+            let mut state = self.ctx.state.lock().unwrap();
+            state.av_info = av_info;
+            state.frame_no = 0;
+            state.discontinuity = true;
+            state.shutdown_requested = false;
+            state.content = Some(String::from(system_name));
+        }
+        // turbo rates are in presses per second of the emulated system
+        self.inputs.lock().unwrap().macros().set_refresh_rate(av_info.timing.fps);
+        // snapshots of the content loaded before are of no use to this one
+        {
+            let mut rewinder = self.rewinder.lock().unwrap();
+            rewinder.set_refresh_rate(av_info.timing.fps);
+            rewinder.clear();
+        }
+
+        println!("{} running {:?}", self.ctx.core.library_name, path);
+        Ok(RetroContent {
+            _path: c_path,
+            _data: data,
+        })
+    }
+
+    fn unload_content(&self, content: Option<RetroContent>) {
+        if content.is_some() {
+            let _core = self.ctx.core_lock.lock().unwrap();
+            unsafe { (self.ctx.core.unload_game)() };
+        }
+        self.ctx.state.lock().unwrap().content = None;
+    }
+
+    // Carries out a reset or a content switch requested since the last frame
+    fn handle_request(&self, content: &mut Option<RetroContent>) -> Result<(), String> {
+        let (request, current) = {
+            let mut state = self.ctx.state.lock().unwrap();
+            (state.pending.take(), state.content.clone())
+        };
+        // libretro has a single kind of reset, a hard one loads the content again
+        let (reload, switched) = match request {
+            Some(RetroRequest::Reset(ResetKind::RESET_SOFT)) => {
+                let _core = self.ctx.core_lock.lock().unwrap();
+                unsafe { (self.ctx.core.reset)() };
+                let mut state = self.ctx.state.lock().unwrap();
+                state.frame_no = 0;
+                state.discontinuity = true;
+                (None, false)
+            },
+            Some(RetroRequest::Reset(ResetKind::RESET_HARD)) => (current, false),
+            Some(RetroRequest::Load(system_name)) => (Some(system_name), true),
+            None => (None, false),
+        };
+
+        if let Some(system_name) = reload {
+            self.unload_content(content.take());
+            // a content the core refuses ends the run, as nothing is left loaded to go on with
+            *content = Some(self.load_content(&system_name)?);
+            if switched {
+                self.lifecycle.notify(EmuEvent::SystemLoaded(system_name));
+            }
+        }
+        Ok(())
+    }
+
+    fn run_content(&self, system_name: &str) -> i32 {
+        let mut content = match self.load_content(system_name) {
+            Ok(content) => Some(content),
+            Err(msg) => {
+                println!("{}", msg);
+                return 1;
+            },
+        };
+
+        let mut ret = 0;
+        let mut next_frame = Instant::now();
+        while !self.lifecycle.is_stop_requested() {
+            if let Err(msg) = self.handle_request(&mut content) {
+                println!("{}", msg);
+                ret = 1;
+                break;
+            }
+
+            let paused = self.lifecycle.is_paused();
+            let (advance, frame_duration) = {
+                let mut state = self.ctx.state.lock().unwrap();
+                let advance = !paused || state.step_pending;
+                if advance {
+                    state.frame_no += 1;
+                    state.step_pending = false;
+
+                    let frame_no = state.frame_no;
+                    self.inputs.lock().unwrap().begin_frame(frame_no, |event| {
+                        LibretroEmulator::apply_input(&mut state, event);
+                    });
+                }
+                let fps = state.av_info.timing.fps;
+                let frame_duration = if state.throttled && fps > 0.0 {
+                    Duration::from_secs_f64(1.0 / (fps * state.speed))
+                } else {
+                    Duration::from_secs(0)
+                };
+                (advance, frame_duration)
+            };
+
+            if advance {
+                self.rewinder.lock().unwrap().frame_begin(|| self.save_state_data());
+                {
+                    let _core = self.ctx.core_lock.lock().unwrap();
+                    unsafe { (self.ctx.core.run)() };
+                }
+                self.ctx.flush_sound();
+            }
+
+            if self.ctx.state.lock().unwrap().shutdown_requested {
+                break;
+            }
+
+            next_frame += frame_duration;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else {
+                next_frame = now;
+            }
+        }
+
+        self.unload_content(content);
+        ret
+    }
+
+    fn slot_path(&self, slot_name: &str) -> Result<PathBuf, String> {
+        validate_slot_name(slot_name)?;
+        let state = self.ctx.state.lock().unwrap();
+        let content = match state.content.as_ref() {
+            Some(content) => content,
+            None => return Err(format!("no content is running")),
+        };
+        let name = Path::new(content).file_stem().map_or(content.clone(), |stem| stem.to_string_lossy().into_owned());
+        Ok(Path::new(&state.state_directory).join(name).join(format!("{}.sta", slot_name)))
+    }
+
+    // the system ram of the core stands in for the program space of any cpu
+    fn with_memory<T>(&self, location: &MemoryLocation, access: impl FnOnce(&mut [u8]) -> T) -> Result<T, String> {
+        location.validate()?;
+        if location.space != AddressSpace::ADDRESS_SPACE_PROGRAM {
+            return Err(format!("no memory at {:?}", location));
+        }
+
+        let _core = self.ctx.core_lock.lock().unwrap();
+        let (data, size) = unsafe {
+            ((self.ctx.core.get_memory_data)(RETRO_MEMORY_SYSTEM_RAM),
+             (self.ctx.core.get_memory_size)(RETRO_MEMORY_SYSTEM_RAM))
+        };
+        if data.is_null() {
+            return Err(format!("{} exposes no system ram", self.ctx.core.library_name));
+        }
+
+        let start = location.address as usize;
+        match start.checked_add(location.width) {
+            Some(end) if end <= size => {
+                let memory = unsafe { slice::from_raw_parts_mut(data as *mut u8, size) };
+                Ok(access(&mut memory[start..end]))
+            },
+            _ => Err(format!("address out of range: {:?}", location)),
+        }
+    }
+}
+
+impl Emulator for LibretroEmulator {
+    // the picture of the core is scaled to w x h
+    fn set_image_frame_info(&mut self, w: usize, h: usize, fps: usize) {
+        {
+            let mut state = self.ctx.state.lock().unwrap();
+            state.w = w;
+            state.h = h;
+        }
+        self.ctx.pacer.lock().unwrap().set_fps(fps);
+    }
+
+    fn set_image_frame_cb(&mut self, callback: impl FnMut(EmuImageFrame) + Send + 'static) -> EmuCallbackGuard {
+        let id = self.next_cb_registration();
+        *self.ctx.image_frame_cb.lock().unwrap() = Some((id, Box::new(callback)));
+
+        let image_frame_cb = &self.ctx.image_frame_cb;
+        EmuCallbackGuard::new(move || {
+            let mut cb = image_frame_cb.lock().unwrap();
+            if cb.as_ref().map_or(false, |(cur, _)| *cur == id) {
+                *cb = None;
+            }
+        })
+    }
+
+    fn set_sound_frame_cb(&mut self, callback: impl FnMut(EmuSoundFrame) + Send + 'static) -> EmuCallbackGuard {
+        let id = self.next_cb_registration();
+        *self.ctx.sound_frame_cb.lock().unwrap() = Some((id, Box::new(callback)));
+
+        let sound_frame_cb = &self.ctx.sound_frame_cb;
+        EmuCallbackGuard::new(move || {
+            let mut cb = sound_frame_cb.lock().unwrap();
+            if cb.as_ref().map_or(false, |(cur, _)| *cur == id) {
+                *cb = None;
+            }
+        })
+    }
+
+    // The rompath is where content and bios files are looked up, saves of the core go
+    // to the nvram directory and extra options are the core's variables. The core picks
+    // its own sample rate, other options are ignored.
+    fn set_options(&mut self, options: &EmulatorOptions) -> Result<(), String> {
+        let mut state = self.ctx.state.lock().unwrap();
+        if let Some(rompath) = options.rompath.as_ref() {
+            state.rompath = rompath.split(';').map(String::from).collect();
+            state.system_directory = CString::new(state.rompath[0].as_str())
+                .map_err(|_| format!("invalid rompath: {}", rompath))?;
+        }
+        if let Some(nvram_directory) = options.nvram_directory.as_ref() {
+            state.save_directory = CString::new(nvram_directory.as_str())
+                .map_err(|_| format!("invalid nvram directory: {}", nvram_directory))?;
+        }
+        if let Some(state_directory) = options.state_directory.as_ref() {
+            state.state_directory = state_directory.clone();
+        }
+        for (name, value) in options.extra.iter() {
+            let c_value = CString::new(value.as_str())
+                .map_err(|_| format!("invalid value of {}: {}", name, value))?;
+            state.variables.insert(name.clone(), c_value);
+            state.variables_updated = true;
+        }
+        Ok(())
+    }
+
+    fn put_input_event(&self, event: EmuInputEvent) {
+        self.inputs.lock().unwrap().put(event);
+    }
+
+    fn set_input_mode(&self, mode: InputMode) {
+        self.inputs.lock().unwrap().set_mode(mode);
+    }
+
+    fn set_turbo(&self, player: u8, button: u8, rate: f64) -> Result<(), String> {
+        self.inputs.lock().unwrap().macros().set_turbo(player, button, rate)
+    }
+
+    fn set_macro(&self, player: u8, name: &str, steps: Vec<MacroStep>) {
+        self.inputs.lock().unwrap().macros().set_macro(player, name, steps);
+    }
+
+    fn run_macro(&self, player: u8, name: &str) -> Result<(), String> {
+        self.inputs.lock().unwrap().macros().run_macro(player, name)
+    }
+
+    fn version(&self) -> String {
+        format!("libretro {} {}", self.ctx.core.library_name, self.ctx.core.library_version)
+    }
+
+    fn run(&self, system_name: &str) -> EmuRunHandle {
+        let system_name = String::from(system_name);
+        let emu = self.clone();
+        let stopper = self.clone();
+        self.lifecycle.spawn(move || stopper.stop(), move || emu.run_content(&system_name))
+    }
+
+    fn stop(&self) {
+        self.lifecycle.request_stop();
+    }
+
+    fn pause(&self) {
+        self.lifecycle.set_paused(true);
+    }
+
+    fn resume(&self) {
+        self.lifecycle.set_paused(false);
+    }
+
+    fn set_speed(&self, speed: f64) -> Result<(), String> {
+        validate_speed(speed)?;
+        self.ctx.state.lock().unwrap().speed = speed;
+        self.ctx.pacer.lock().unwrap().set_speed(speed);
+        Ok(())
+    }
+
+    fn set_throttled(&self, throttled: bool) {
+        self.ctx.state.lock().unwrap().throttled = throttled;
+        self.ctx.pacer.lock().unwrap().set_throttled(throttled);
+    }
+
+    fn step_frame(&self) -> Result<(), String> {
+        if !self.lifecycle.is_paused() {
+            return Err(format!("frames can only be stepped while paused"));
+        }
+
+        self.ctx.state.lock().unwrap().step_pending = true;
+        self.ctx.pacer.lock().unwrap().force_next();
+        Ok(())
+    }
+
+    fn reset(&self, kind: ResetKind) {
+        self.ctx.state.lock().unwrap().pending = Some(RetroRequest::Reset(kind));
+        self.lifecycle.notify(EmuEvent::Reset(kind));
+    }
+
+    fn load_system(&self, system_name: &str) -> Result<(), String> {
+        self.find_content(system_name)?;
+        {
+            let mut state = self.ctx.state.lock().unwrap();
+            if state.content.is_none() {
+                return Err(format!("no content is running"));
+            }
+            // SystemLoaded is notified once the core has taken the content
+            state.pending = Some(RetroRequest::Load(String::from(system_name)));
+        }
+        Ok(())
+    }
+
+    fn set_rewind(&self, settings: Option<RewindSettings>) -> Result<(), String> {
+        self.rewinder.lock().unwrap().configure(settings)
+    }
+
+    fn rewind(&self, seconds: f64) -> Result<(), String> {
+        let data = self.rewinder.lock().unwrap().rewind(seconds)?;
+        self.load_state_data(&data)?;
+        self.ctx.state.lock().unwrap().discontinuity = true;
+        Ok(())
+    }
+
+    fn save_state(&self, slot_name: &str) -> Result<(), String> {
+        let path = self.slot_path(slot_name)?;
+        let data = self.save_state_data()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("failed to create {:?}: {}", dir, e))?;
+        }
+        fs::write(&path, data).map_err(|e| format!("failed to save state to slot {}: {}", slot_name, e))
+    }
+
+    fn load_state(&self, slot_name: &str) -> Result<(), String> {
+        let path = self.slot_path(slot_name)?;
+        let data = fs::read(&path).map_err(|e| format!("failed to load state from slot {}: {}", slot_name, e))?;
+        self.load_state_data(&data)
+    }
+
+    fn save_state_data(&self) -> Result<Vec<u8>, String> {
+        let _core = self.ctx.core_lock.lock().unwrap();
+        let state_size = unsafe { (self.ctx.core.serialize_size)() };
+        if state_size == 0 {
+            return Err(format!("state is not available for the running content"));
+        }
+
+        let mut data = vec![0u8; state_size];
+        if unsafe { (self.ctx.core.serialize)(data.as_mut_ptr() as *mut c_void, data.len()) } {
+            Ok(data)
+        } else {
+            Err(format!("failed to save state to buffer"))
+        }
+    }
+
+    fn load_state_data(&self, data: &[u8]) -> Result<(), String> {
+        let _core = self.ctx.core_lock.lock().unwrap();
+        if unsafe { (self.ctx.core.unserialize)(data.as_ptr() as *const c_void, data.len()) } {
+            Ok(())
+        } else {
+            Err(format!("failed to load state from buffer of {} bytes", data.len()))
+        }
+    }
+
+    fn read_memory(&self, location: &MemoryLocation) -> Result<u64, String> {
+        self.with_memory(location, |memory| {
+            memory.iter().rev().fold(0u64, |value, b| (value << 8) | u64::from(*b))
+        })
+    }
+
+    fn write_memory(&self, location: &MemoryLocation, value: u64) -> Result<(), String> {
+        self.with_memory(location, |memory| {
+            let len = memory.len();
+            memory.copy_from_slice(&value.to_le_bytes()[..len]);
+        })
+    }
+
+    // the content file is the only "rom" of a libretro system
+    fn audit_system(&self, system_name: &str) -> Result<RomAudit, String> {
+        let record = match self.find_content(system_name) {
+            Ok(path) => {
+                let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                RomAuditRecord {
+                    name: path.to_string_lossy().into_owned(),
+                    status: RomStatus::ROM_STATUS_GOOD,
+                    expected_size: size,
+                    actual_size: size,
+                    optional: false,
+                }
+            },
+            Err(_) => RomAuditRecord {
+                name: String::from(system_name),
+                status: RomStatus::ROM_STATUS_NOT_FOUND,
+                expected_size: 0,
+                actual_size: 0,
+                optional: false,
+            },
+        };
+        Ok(RomAudit {
+            system_name: String::from(system_name),
+            records: vec![record],
+        })
+    }
+
+    // the screen is only known once content is loaded
+    fn system_info(&self, system_name: &str) -> Result<SystemInfo, String> {
+        let av_info = self.ctx.state.lock().unwrap().av_info;
+        Ok(SystemInfo {
+            system_name: String::from(system_name),
+            description: format!("{} {}", self.ctx.core.library_name, self.ctx.core.library_version),
+            year: String::new(),
+            manufacturer: String::new(),
+            screen_width: av_info.geometry.base_width as usize,
+            screen_height: av_info.geometry.base_height as usize,
+            orientation: Orientation::ORIENTATION_ROT0,
+            refresh_rate: av_info.timing.fps,
+            players: PLAYERS,
+            buttons: JOYPAD_BUTTONS,
+        })
+    }
+}
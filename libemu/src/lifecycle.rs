@@ -0,0 +1,163 @@
+use std::panic;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use crate::ResetKind;
+
+#[derive(Debug, Clone)]
+pub enum EmuEvent {
+    Started,
+    Paused,
+    Resumed,
+    Reset(ResetKind),
+    // the running machine switched to another system
+    SystemLoaded(String),
+    GameExited(i32),
+    Error(String),
+    // every input of the replay being played back has been applied
+    ReplayFinished,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmuExitReason {
+    Stopped,
+    GameExited,
+    Error(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct EmuExitStatus {
+    pub code: i32,
+    pub reason: EmuExitReason,
+}
+
+// Lifecycle state shared by all clones of an emulator, so that pause, resume and stop
+// issued from any clone are reported to the subscribers of the running handle.
+#[derive(Clone, Default)]
+pub struct Lifecycle {
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<EmuEvent>>>>,
+    stop_requested: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+}
+
+impl Lifecycle {
+    pub fn subscribe(&self) -> mpsc::Receiver<EmuEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn notify(&self, event: EmuEvent) {
+        self.subscribers.lock().unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    pub fn request_stop(&self) {
+        self.stop_requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stop_requested(&self) -> bool {
+        self.stop_requested.load(Ordering::SeqCst)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        self.notify(if paused { EmuEvent::Paused } else { EmuEvent::Resumed });
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    // Runs the emulation body on its own thread, reporting start and exit as events.
+    pub fn spawn(
+        &self,
+        stop: impl Fn() + Send + 'static,
+        body: impl FnOnce() -> i32 + Send + 'static) -> EmuRunHandle {
+
+        self.stop_requested.store(false, Ordering::SeqCst);
+        self.paused.store(false, Ordering::SeqCst);
+        let events = self.subscribe();
+
+        let lifecycle = self.clone();
+        let join_handle = thread::spawn(move || {
+            lifecycle.notify(EmuEvent::Started);
+            // subscribers wait for the run to end, which they'd never hear of from a panicking body
+            let code = match panic::catch_unwind(panic::AssertUnwindSafe(body)) {
+                Ok(code) => code,
+                Err(cause) => {
+                    lifecycle.notify(EmuEvent::Error(format!("emulator thread panicked")));
+                    panic::resume_unwind(cause);
+                },
+            };
+            if code == 0 || lifecycle.is_stop_requested() {
+                lifecycle.notify(EmuEvent::GameExited(code));
+            } else {
+                lifecycle.notify(EmuEvent::Error(format!("emulator exited with code {}", code)));
+            }
+            code
+        });
+
+        EmuRunHandle {
+            join_handle: join_handle,
+            events: events,
+            stop: Box::new(stop),
+            lifecycle: self.clone(),
+        }
+    }
+}
+
+pub struct EmuRunHandle {
+    join_handle: thread::JoinHandle<i32>,
+    events: mpsc::Receiver<EmuEvent>,
+    stop: Box<dyn Fn() + Send>,
+    lifecycle: Lifecycle,
+}
+
+impl EmuRunHandle {
+    pub fn events(&self) -> &mpsc::Receiver<EmuEvent> {
+        &self.events
+    }
+
+    pub fn stop(&self) {
+        (self.stop)();
+    }
+
+    pub fn wait(self) -> EmuExitStatus {
+        match self.join_handle.join() {
+            Ok(code) if self.lifecycle.is_stop_requested() => EmuExitStatus {
+                code: code,
+                reason: EmuExitReason::Stopped,
+            },
+            Ok(0) => EmuExitStatus {
+                code: 0,
+                reason: EmuExitReason::GameExited,
+            },
+            Ok(code) => EmuExitStatus {
+                code: code,
+                reason: EmuExitReason::Error(format!("emulator exited with code {}", code)),
+            },
+            Err(_) => EmuExitStatus {
+                code: -1,
+                reason: EmuExitReason::Error(String::from("emulator thread panicked")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_panicking_run_ends_with_an_error() {
+        let handle = Lifecycle::default().spawn(|| {}, || panic!("emulator crashed"));
+        let errored = handle.events().iter().any(|event| matches!(event, EmuEvent::Error(_)));
+        assert!(errored);
+        match handle.wait().reason {
+            EmuExitReason::Error(_) => {},
+            reason => panic!("unexpected exit reason: {:?}", reason),
+        }
+    }
+}
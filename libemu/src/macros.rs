@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use crate::{EmuInputEvent, InputKind};
+
+// refresh rate assumed until the emulator reports the one of the running system
+const DEFAULT_REFRESH_RATE: f64 = 60.0;
+
+// offset is the number of emulated frames after the start of the macro
+#[derive(Debug, Clone)]
+pub struct MacroStep {
+    pub offset: u32,
+    pub event: EmuInputEvent,
+}
+
+// rate is the number of presses per second while the button is held
+struct Turbo {
+    rate: f64,
+    held_since: Option<u64>,
+    pressed: bool,
+}
+
+// Turbo buttons and macros of every player, expanded into plain input events frame by frame
+// on the emulation thread, so that their timing doesn't depend on the network.
+pub(crate) struct InputMacros {
+    refresh_rate: f64,
+    turbos: HashMap<(u8, u8), Turbo>,
+    macros: HashMap<(u8, String), Vec<MacroStep>>,
+    starting: Vec<Vec<MacroStep>>,
+    scheduled: Vec<(u64, EmuInputEvent)>,
+    last_frame_number: Option<u64>,
+}
+
+impl InputMacros {
+    pub fn new() -> InputMacros {
+        InputMacros {
+            refresh_rate: DEFAULT_REFRESH_RATE,
+            turbos: HashMap::new(),
+            macros: HashMap::new(),
+            starting: vec![],
+            scheduled: vec![],
+            last_frame_number: None,
+        }
+    }
+
+    pub fn set_refresh_rate(&mut self, refresh_rate: f64) {
+        if refresh_rate > 0.0 {
+            self.refresh_rate = refresh_rate;
+        }
+    }
+
+    // a rate of 0 turns turbo off, releasing the button if turbo holds it down
+    pub fn set_turbo(&mut self, player: u8, button: u8, rate: f64) -> Result<(), String> {
+        if !(rate >= 0.0) {
+            return Err(format!("invalid turbo rate: {}", rate));
+        }
+
+        let released = if rate == 0.0 {
+            self.turbos.remove(&(player, button)).map_or(false, |turbo| turbo.pressed)
+        } else {
+            let turbo = self.turbos.entry((player, button)).or_insert(Turbo {
+                rate: rate,
+                held_since: None,
+                pressed: false,
+            });
+            turbo.rate = rate;
+            false
+        };
+        if released {
+            self.scheduled.push((0, EmuInputEvent::button(player, button, InputKind::INPUT_BUTTON_UP)));
+        }
+        Ok(())
+    }
+
+    // no steps removes the macro
+    pub fn set_macro(&mut self, player: u8, name: &str, mut steps: Vec<MacroStep>) {
+        if steps.is_empty() {
+            self.macros.remove(&(player, String::from(name)));
+        } else {
+            steps.sort_by_key(|step| step.offset);
+            self.macros.insert((player, String::from(name)), steps);
+        }
+    }
+
+    // the macro starts on the next emulated frame
+    pub fn run_macro(&mut self, player: u8, name: &str) -> Result<(), String> {
+        match self.macros.get(&(player, String::from(name))) {
+            Some(steps) => {
+                self.starting.push(steps.clone());
+                Ok(())
+            },
+            None => Err(format!("no macro {} for player {}", name, player)),
+        }
+    }
+
+    // Takes the events put in for frame_number and returns the events to apply to it.
+    // Presses and releases of turbo buttons are taken over by the turbo.
+    pub fn expand(&mut self, frame_number: u64, events: Vec<EmuInputEvent>) -> Vec<EmuInputEvent> {
+        // the frame counter starts over after a reset or a system switch, carry on from there
+        if let Some(last) = self.last_frame_number.filter(|last| frame_number < *last) {
+            for (frame, _) in self.scheduled.iter_mut() {
+                *frame = (*frame + frame_number).saturating_sub(last);
+            }
+            for turbo in self.turbos.values_mut() {
+                turbo.held_since = turbo.held_since.map(|_| frame_number);
+            }
+        }
+        self.last_frame_number = Some(frame_number);
+
+        let mut expanded = vec![];
+        for event in events {
+            let turbo = match event.kind {
+                InputKind::INPUT_BUTTON_DOWN | InputKind::INPUT_BUTTON_UP =>
+                    self.turbos.get_mut(&(event.player, event.value)),
+                _ => None,
+            };
+            match turbo {
+                Some(turbo) if event.kind == InputKind::INPUT_BUTTON_DOWN => {
+                    turbo.held_since = turbo.held_since.or(Some(frame_number));
+                },
+                Some(turbo) => turbo.held_since = None,
+                None => expanded.push(event),
+            }
+        }
+
+        for steps in self.starting.drain(..) {
+            for step in steps {
+                self.scheduled.push((frame_number + step.offset as u64, step.event));
+            }
+        }
+        let (due, later): (Vec<_>, Vec<_>) = self.scheduled.drain(..)
+            .partition(|(frame, _)| *frame <= frame_number);
+        self.scheduled = later;
+        expanded.extend(due.into_iter().map(|(_, event)| event));
+
+        let refresh_rate = self.refresh_rate;
+        for ((player, button), turbo) in self.turbos.iter_mut() {
+            // pressed for the first half of every period, a period being at least two frames
+            let half_period = (refresh_rate / turbo.rate / 2.0).max(1.0);
+            let pressed = turbo.held_since.map_or(false, |since| {
+                ((frame_number - since) as f64 / half_period).floor() as u64 % 2 == 0
+            });
+            if pressed != turbo.pressed {
+                turbo.pressed = pressed;
+                let kind = if pressed { InputKind::INPUT_BUTTON_DOWN } else { InputKind::INPUT_BUTTON_UP };
+                expanded.push(EmuInputEvent::button(*player, *button, kind));
+            }
+        }
+
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOWN: InputKind = InputKind::INPUT_BUTTON_DOWN;
+    const UP: InputKind = InputKind::INPUT_BUTTON_UP;
+
+    fn buttons(events: Vec<EmuInputEvent>) -> Vec<(u8, u8, InputKind)> {
+        events.iter().map(|event| (event.player, event.value, event.kind)).collect()
+    }
+
+    fn step(offset: u32, button: u8, kind: InputKind) -> MacroStep {
+        MacroStep { offset: offset, event: EmuInputEvent::button(0, button, kind) }
+    }
+
+    #[test]
+    fn turbo_presses_and_releases_at_its_rate() {
+        let mut macros = InputMacros::new();
+        // 15 presses a second at 60 Hz, down for 2 frames and up for 2
+        macros.set_turbo(0, 1, 15.0).unwrap();
+        assert_eq!(buttons(macros.expand(10, vec![EmuInputEvent::button(0, 1, DOWN)])), vec![(0, 1, DOWN)]);
+        let cadence: Vec<_> = (11..=18).map(|frame_number| buttons(macros.expand(frame_number, vec![]))).collect();
+        assert_eq!(cadence, vec![vec![], vec![(0, 1, UP)], vec![], vec![(0, 1, DOWN)],
+                                 vec![], vec![(0, 1, UP)], vec![], vec![(0, 1, DOWN)]]);
+
+        // letting go releases the button, other buttons go through as they are
+        let events = vec![EmuInputEvent::button(0, 1, UP), EmuInputEvent::button(0, 2, DOWN)];
+        assert_eq!(buttons(macros.expand(19, events)), vec![(0, 2, DOWN), (0, 1, UP)]);
+        assert!(macros.expand(20, vec![]).is_empty());
+    }
+
+    #[test]
+    fn macro_steps_come_out_on_their_frames() {
+        let mut macros = InputMacros::new();
+        macros.set_macro(0, "jab", vec![step(8, 1, UP), step(0, 2, DOWN), step(4, 1, DOWN), step(4, 2, UP)]);
+        macros.run_macro(0, "jab").unwrap();
+        assert!(macros.run_macro(1, "jab").is_err());
+
+        let frames: Vec<_> = (100..=110)
+            .map(|frame_number| (frame_number, buttons(macros.expand(frame_number, vec![]))))
+            .filter(|(_, events)| !events.is_empty())
+            .collect();
+        assert_eq!(frames, vec![
+            (100, vec![(0, 2, DOWN)]),
+            (104, vec![(0, 1, DOWN), (0, 2, UP)]),
+            (108, vec![(0, 1, UP)]),
+        ]);
+    }
+
+    #[test]
+    fn a_reset_carries_on_with_what_is_pending() {
+        let mut macros = InputMacros::new();
+        macros.set_macro(0, "jab", vec![step(0, 1, DOWN), step(8, 1, UP)]);
+        macros.run_macro(0, "jab").unwrap();
+        macros.set_turbo(0, 2, 15.0).unwrap();
+        macros.expand(100, vec![EmuInputEvent::button(0, 2, DOWN)]);
+        macros.expand(101, vec![]);
+        macros.expand(102, vec![]);
+
+        // the frame counter starts over, the release stays 6 frames away and turbo starts a new press
+        assert_eq!(buttons(macros.expand(1, vec![])), vec![(0, 2, DOWN)]);
+        let frames: Vec<_> = (2..=7).map(|frame_number| buttons(macros.expand(frame_number, vec![]))).collect();
+        assert_eq!(frames, vec![vec![], vec![(0, 2, UP)], vec![], vec![(0, 2, DOWN)], vec![],
+                                vec![(0, 1, UP), (0, 2, UP)]]);
+    }
+
+    #[test]
+    fn cleared_turbos_and_macros_are_gone() {
+        let mut macros = InputMacros::new();
+        macros.set_turbo(0, 1, 15.0).unwrap();
+        macros.expand(1, vec![EmuInputEvent::button(0, 1, DOWN)]);
+        // turning turbo off releases the button it holds down, presses then go through as they are
+        macros.set_turbo(0, 1, 0.0).unwrap();
+        assert_eq!(buttons(macros.expand(2, vec![])), vec![(0, 1, UP)]);
+        assert_eq!(buttons(macros.expand(3, vec![EmuInputEvent::button(0, 1, DOWN)])), vec![(0, 1, DOWN)]);
+        assert!(macros.set_turbo(0, 1, -1.0).is_err());
+
+        macros.set_macro(0, "jab", vec![step(0, 1, DOWN)]);
+        macros.set_macro(0, "jab", vec![]);
+        assert!(macros.run_macro(0, "jab").is_err());
+    }
+}
@@ -0,0 +1,101 @@
+// Runtime options of the emulator, None leaving the option at the emulator's default.
+// extra holds any other mame option as a name (without the dash) and value pair.
+// run_ahead is the number of frames emulated ahead to hide input latency, see MameEmulator.
+#[derive(Debug, Clone, Default)]
+pub struct EmulatorOptions {
+    pub rompath: Option<String>,
+    pub nvram_directory: Option<String>,
+    pub cfg_directory: Option<String>,
+    pub state_directory: Option<String>,
+    pub bgfx_screen_chains: Option<String>,
+    pub video: Option<String>,
+    pub sample_rate: Option<usize>,
+    pub run_ahead: Option<u32>,
+    pub extra: Vec<(String, String)>,
+}
+
+impl EmulatorOptions {
+    // Returns a copy with the options set in other taking precedence
+    pub fn overlay(&self, other: &EmulatorOptions) -> EmulatorOptions {
+        EmulatorOptions {
+            rompath: other.rompath.clone().or(self.rompath.clone()),
+            nvram_directory: other.nvram_directory.clone().or(self.nvram_directory.clone()),
+            cfg_directory: other.cfg_directory.clone().or(self.cfg_directory.clone()),
+            state_directory: other.state_directory.clone().or(self.state_directory.clone()),
+            bgfx_screen_chains: other.bgfx_screen_chains.clone().or(self.bgfx_screen_chains.clone()),
+            video: other.video.clone().or(self.video.clone()),
+            sample_rate: other.sample_rate.or(self.sample_rate),
+            run_ahead: other.run_ahead.or(self.run_ahead),
+            extra: self.extra.iter()
+                .filter(|(name, _)| !other.extra.iter().any(|(n, _)| n == name))
+                .chain(other.extra.iter())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    // mame option names and values in the order they are to be applied, run_ahead not being one
+    pub fn to_mame_options(&self) -> Vec<(String, String)> {
+        let named = [
+            ("rompath", self.rompath.clone()),
+            ("nvram_directory", self.nvram_directory.clone()),
+            ("cfg_directory", self.cfg_directory.clone()),
+            ("state_directory", self.state_directory.clone()),
+            ("bgfx_screen_chains", self.bgfx_screen_chains.clone()),
+            ("video", self.video.clone()),
+            ("samplerate", self.sample_rate.map(|rate| rate.to_string())),
+        ];
+
+        named.iter()
+            .filter_map(|(name, val)| val.as_ref().map(|v| (String::from(*name), v.clone())))
+            .chain(self.extra.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_prefers_the_other_options() {
+        let base = EmulatorOptions {
+            rompath: Some(String::from("./roms")),
+            video: Some(String::from("bgfx")),
+            extra: vec![(String::from("bgfx_path"), String::from("./bgfx")), (String::from("skip_gameinfo"), String::from("1"))],
+            ..Default::default()
+        };
+        let other = EmulatorOptions {
+            video: Some(String::from("soft")),
+            run_ahead: Some(2),
+            extra: vec![(String::from("bgfx_path"), String::from("./other"))],
+            ..Default::default()
+        };
+
+        let options = base.overlay(&other);
+        assert_eq!(options.rompath.as_deref(), Some("./roms"));
+        assert_eq!(options.video.as_deref(), Some("soft"));
+        assert_eq!(options.run_ahead, Some(2));
+        assert_eq!(options.extra, vec![
+            (String::from("skip_gameinfo"), String::from("1")),
+            (String::from("bgfx_path"), String::from("./other")),
+        ]);
+    }
+
+    #[test]
+    fn mame_options_leave_out_what_is_unset() {
+        let options = EmulatorOptions {
+            rompath: Some(String::from("./roms")),
+            sample_rate: Some(48000),
+            run_ahead: Some(1),
+            extra: vec![(String::from("artwork_crop"), String::from("1"))],
+            ..Default::default()
+        };
+
+        assert_eq!(options.to_mame_options(), vec![
+            (String::from("rompath"), String::from("./roms")),
+            (String::from("samplerate"), String::from("48000")),
+            (String::from("artwork_crop"), String::from("1")),
+        ]);
+    }
+}
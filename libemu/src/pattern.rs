@@ -0,0 +1,598 @@
+use std::f32::consts::PI;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libbuf::BufferPool;
+
+use crate::{Emulator, EmuCallbackGuard, EmuImageFrame, EmuRunHandle, EmuSoundFrame, EmuInputEvent};
+use crate::{AddressSpace, EmuEvent, InputKind, MemoryLocation, PixelFormat, ResetKind};
+use crate::{EmulatorOptions, MacroStep, Orientation, RewindSettings, RomAudit, SystemInfo};
+use crate::lifecycle::Lifecycle;
+use crate::replay::{InputMode, InputScheduler};
+use crate::rewind::Rewinder;
+use crate::utils;
+use crate::{FRAME_POOL_SIZE, validate_slot_name, validate_speed};
+
+const BYTES_PER_PIXEL: usize = 4;
+
+const REFRESH_RATE: f64 = 60.0;
+const DEFAULT_SAMPLE_RATE: usize = 48000;
+const CHANNELS: usize = 2;
+const TONE_HZ: f32 = 440.0;
+const TONE_AMPLITUDE: f32 = 8000.0;
+
+const SWEEP_PIXELS_PER_FRAME: usize = 2;
+const KEY_CELLS: usize = 32;
+const BUTTONS_PER_PLAYER: usize = 8;
+const CROSSHAIR_SIZE: i32 = 6;
+
+// A little endian 64KiB RAM in the program space of a single cpu, where the frame counter
+// (u32 at 0x0000) and the number of held keys (u8 at 0x0004) are mirrored every frame.
+const MEMORY_CPU_TAG: &str = ":maincpu";
+const MEMORY_SIZE: usize = 0x10000;
+const MEMORY_FRAME_NO_ADDR: usize = 0x0000;
+const MEMORY_PRESSED_ADDR: usize = 0x0004;
+
+// 75% colour bars in BGRA order
+const COLOR_BARS: [[u8; 4]; 7] = [
+    [191, 191, 191, 255],
+    [0, 191, 191, 255],
+    [191, 191, 0, 255],
+    [0, 191, 0, 255],
+    [191, 0, 191, 255],
+    [0, 0, 191, 255],
+    [191, 0, 0, 255],
+];
+
+// callbacks are tagged with a registration id so that a stale guard doesn't clear a newer one
+type ImageFrameCb = (u64, Box<dyn FnMut(EmuImageFrame) + Send>);
+type SoundFrameCb = (u64, Box<dyn FnMut(EmuSoundFrame) + Send>);
+
+struct PatternState {
+    w: usize,
+    h: usize,
+    pacer: utils::pacing::FramePacer,
+    speed: f64,
+    throttled: bool,
+    step_pending: bool,
+    pressed: Vec<usize>,
+    pointer: Option<(i32, i32)>,
+    frame_no: u64,
+    discontinuity: bool,
+    memory: Vec<u8>,
+    sample_rate: usize,
+    slots: HashMap<String, Vec<u8>>,
+    cb_registration: u64,
+}
+
+// Synthetic emulator which renders colour bars with a moving sweep and a sine tone
+// at a 60Hz emulated refresh rate, so that the encoding pipeline can be exercised
+// without libmame.
+#[derive(Clone)]
+pub struct PatternEmulator {
+    state: Arc<Mutex<PatternState>>,
+    image_frame_cb: Arc<Mutex<Option<ImageFrameCb>>>,
+    sound_frame_cb: Arc<Mutex<Option<SoundFrameCb>>>,
+    lifecycle: Lifecycle,
+    inputs: Arc<Mutex<InputScheduler>>,
+    rewinder: Arc<Mutex<Rewinder>>,
+    image_pool: BufferPool<u8>,
+    sound_pool: BufferPool<i16>,
+}
+
+impl PatternEmulator {
+    pub fn create(w: usize, h: usize, fps: usize) -> impl Emulator {
+        let lifecycle = Lifecycle::default();
+        let mut emu = PatternEmulator {
+            state: Arc::new(Mutex::new(PatternState {
+                w: w,
+                h: h,
+                pacer: utils::pacing::FramePacer::new(fps),
+                speed: 1.0,
+                throttled: true,
+                step_pending: false,
+                pressed: vec![],
+                pointer: None,
+                frame_no: 0,
+                discontinuity: false,
+                memory: vec![0u8; MEMORY_SIZE],
+                sample_rate: DEFAULT_SAMPLE_RATE,
+                slots: HashMap::new(),
+                cb_registration: 0,
+            })),
+            image_frame_cb: Arc::new(Mutex::new(None)),
+            sound_frame_cb: Arc::new(Mutex::new(None)),
+            lifecycle: lifecycle.clone(),
+            inputs: Arc::new(Mutex::new(InputScheduler::new(lifecycle))),
+            rewinder: Arc::new(Mutex::new(Rewinder::new())),
+            image_pool: BufferPool::create(FRAME_POOL_SIZE),
+            sound_pool: BufferPool::create(FRAME_POOL_SIZE),
+        };
+
+        emu.set_image_frame_info(w, h, fps);
+        emu
+    }
+
+    fn render_image(
+        buf: &mut [u8], w: usize, h: usize, frame_no: u64, pressed: &[usize], pointer: Option<(i32, i32)>) {
+
+        let bars_h = h * 2 / 3;
+        let keys_h = (h - bars_h) / 2;
+        let sweep_x = (frame_no as usize * SWEEP_PIXELS_PER_FRAME) % w.max(1);
+        let cell_w = (w / KEY_CELLS).max(1);
+
+        for y in 0..h {
+            for x in 0..w {
+                let pixel = if y < bars_h {
+                    COLOR_BARS[x * COLOR_BARS.len() / w]
+                } else if y < bars_h + keys_h {
+                    // a cell lights up for every key or button being held
+                    if pressed.contains(&(x / cell_w)) {
+                        [255, 255, 255, 255]
+                    } else {
+                        [16, 16, 16, 255]
+                    }
+                } else if x >= sweep_x && x < sweep_x + SWEEP_PIXELS_PER_FRAME * 2 {
+                    [255, 255, 255, 255]
+                } else {
+                    let v = (x * 255 / w) as u8;
+                    [v, v, v, 255]
+                };
+
+                let offset = (y * w + x) * BYTES_PER_PIXEL;
+                buf[offset..offset + BYTES_PER_PIXEL].copy_from_slice(&pixel);
+            }
+        }
+
+        if let Some((px, py)) = pointer {
+            for d in -CROSSHAIR_SIZE..=CROSSHAIR_SIZE {
+                for (x, y) in [(px + d, py), (px, py + d)].iter() {
+                    if *x >= 0 && *y >= 0 && (*x as usize) < w && (*y as usize) < h {
+                        let offset = (*y as usize * w + *x as usize) * BYTES_PER_PIXEL;
+                        buf[offset..offset + BYTES_PER_PIXEL].copy_from_slice(&[0, 0, 255, 255]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn next_cb_registration(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.cb_registration += 1;
+        state.cb_registration
+    }
+
+    fn input_cell(event: &EmuInputEvent) -> usize {
+        match event.kind {
+            InputKind::INPUT_BUTTON_DOWN | InputKind::INPUT_BUTTON_UP =>
+                (event.player as usize * BUTTONS_PER_PLAYER + event.value as usize) % KEY_CELLS,
+            _ => event.value as usize % KEY_CELLS,
+        }
+    }
+
+    fn apply_input(state: &mut PatternState, event: &EmuInputEvent) {
+        let cell = PatternEmulator::input_cell(event);
+        match event.kind {
+            InputKind::INPUT_KEY_DOWN | InputKind::INPUT_BUTTON_DOWN => {
+                if !state.pressed.contains(&cell) {
+                    state.pressed.push(cell);
+                }
+            },
+            InputKind::INPUT_KEY_UP | InputKind::INPUT_BUTTON_UP => state.pressed.retain(|c| *c != cell),
+            InputKind::INPUT_POINTER_MOVE => state.pointer = Some((event.x, event.y)),
+            InputKind::INPUT_AXIS_ABSOLUTE | InputKind::INPUT_AXIS_RELATIVE => {},
+        }
+    }
+
+    // the frame counter is all there is to the state of the pattern
+    fn state_data(state: &PatternState) -> Vec<u8> {
+        state.frame_no.to_le_bytes().to_vec()
+    }
+
+    fn memory_range(location: &MemoryLocation) -> Result<std::ops::Range<usize>, String> {
+        location.validate()?;
+        if location.cpu_tag != MEMORY_CPU_TAG || location.space != AddressSpace::ADDRESS_SPACE_PROGRAM {
+            return Err(format!("no memory at {:?}", location));
+        }
+        let start = location.address as usize;
+        match start.checked_add(location.width) {
+            Some(end) if end <= MEMORY_SIZE => Ok(start..end),
+            _ => Err(format!("address out of range: {:?}", location)),
+        }
+    }
+
+    fn render_sound(
+        buf: &mut [i16], samples: usize, sample_rate: usize, freq: f32, silent: bool, phase: &mut f32) {
+
+        let step = 2.0 * PI * freq / sample_rate as f32;
+
+        for i in 0..samples {
+            let v = if silent { 0 } else { (phase.sin() * TONE_AMPLITUDE) as i16 };
+            for c in 0..CHANNELS {
+                buf[i * CHANNELS + c] = v;
+            }
+            *phase = (*phase + step) % (2.0 * PI);
+        }
+    }
+
+    fn run_frames(&self) -> i32 {
+        let mut phase: f32 = 0.0;
+        let mut sound_clock = utils::pacing::SoundClock::new();
+        let mut next_frame = Instant::now();
+        // options take effect from the next run, as they do with mame
+        let sample_rate = self.state.lock().unwrap().sample_rate;
+
+        while !self.lifecycle.is_stop_requested() {
+            let paused = self.lifecycle.is_paused();
+            let (w, h, pressed, pointer, frame_no, picked, discontinuity, sound_timing, silence, frame_duration) = {
+                let mut state = self.state.lock().unwrap();
+                let advance = !paused || state.step_pending;
+                if advance {
+                    state.frame_no += 1;
+                    state.step_pending = false;
+
+                    let frame_no = state.frame_no;
+                    self.inputs.lock().unwrap().begin_frame(frame_no, |event| {
+                        PatternEmulator::apply_input(&mut state, event);
+                    });
+                    self.rewinder.lock().unwrap().frame_begin(|| Ok(PatternEmulator::state_data(&state)));
+
+                    let pressed = state.pressed.len() as u8;
+                    state.memory[MEMORY_FRAME_NO_ADDR..MEMORY_FRAME_NO_ADDR + 4]
+                        .copy_from_slice(&(frame_no as u32).to_le_bytes());
+                    state.memory[MEMORY_PRESSED_ADDR] = pressed;
+                }
+                let frame_no = state.frame_no;
+                let picked = state.pacer.pick(frame_no, REFRESH_RATE);
+                let discontinuity = picked.is_some() && state.discontinuity;
+                if discontinuity {
+                    state.discontinuity = false;
+                }
+                let frame_duration = if state.throttled {
+                    Duration::from_secs_f64(1.0 / (REFRESH_RATE * state.speed))
+                } else {
+                    Duration::from_secs(0)
+                };
+                let realtime = state.pacer.is_realtime();
+                let silence = if realtime {
+                    None
+                } else {
+                    sound_clock.place_silence(&state.pacer, sample_rate)
+                };
+                let sound_timing = if !realtime {
+                    None
+                } else if advance {
+                    Some(sound_clock.place(&state.pacer, sample_rate / REFRESH_RATE as usize, sample_rate))
+                } else {
+                    // like mame, no sound is put out while paused, the video clock standing still meanwhile
+                    None
+                };
+                (state.w, state.h, state.pressed.clone(), state.pointer, frame_no, picked, discontinuity,
+                 sound_timing, silence, frame_duration)
+            };
+
+            if let Some(emu_timestamp) = picked {
+                if let Some((_, cb)) = self.image_frame_cb.lock().unwrap().as_mut() {
+                    let mut buf = self.image_pool.acquire(w * h * BYTES_PER_PIXEL);
+                    PatternEmulator::render_image(&mut buf, w, h, frame_no, &pressed, pointer);
+                    cb(EmuImageFrame {
+                        buf: buf,
+                        width: w,
+                        height: h,
+                        stride: w * BYTES_PER_PIXEL,
+                        format: PixelFormat::PIXEL_FORMAT_BGRA32,
+                        frame_number: frame_no,
+                        timestamp: utils::time::now_utc(),
+                        emu_timestamp: emu_timestamp,
+                        discontinuity: discontinuity,
+                    });
+                }
+            }
+
+            // like mame, sound is only passed on at realtime speed, silence stands in for it otherwise
+            if let (Some((emu_timestamp, samples)), Some((_, cb))) = (silence, self.sound_frame_cb.lock().unwrap().as_mut()) {
+                cb(EmuSoundFrame {
+                    samples: samples,
+                    buf: utils::pacing::silence(&self.sound_pool, samples, CHANNELS),
+                    sample_rate: sample_rate,
+                    channels: CHANNELS,
+                    timestamp: utils::time::now_utc(),
+                    emu_timestamp: emu_timestamp,
+                });
+            }
+            if let (Some(timing), Some((_, cb))) = (sound_timing, self.sound_frame_cb.lock().unwrap().as_mut()) {
+                // holding any key or button raises the tone by an octave
+                let freq = if pressed.is_empty() { TONE_HZ } else { TONE_HZ * 2.0 };
+                let samples = sample_rate / REFRESH_RATE as usize;
+                let mut buf = self.sound_pool.acquire(samples * CHANNELS);
+                PatternEmulator::render_sound(&mut buf, samples, sample_rate, freq, paused, &mut phase);
+                if timing.correction != 0 {
+                    buf = utils::pacing::correct_samples(&self.sound_pool, &buf, CHANNELS, timing.correction);
+                }
+                cb(EmuSoundFrame {
+                    samples: buf.len() / CHANNELS,
+                    buf: buf,
+                    sample_rate: sample_rate,
+                    channels: CHANNELS,
+                    timestamp: utils::time::now_utc(),
+                    emu_timestamp: timing.emu_timestamp,
+                });
+            }
+
+            next_frame += frame_duration;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else {
+                next_frame = now;
+            }
+        }
+
+        0
+    }
+}
+
+impl Emulator for PatternEmulator {
+    fn set_image_frame_info(&mut self, w: usize, h: usize, fps: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.w = w;
+        state.h = h;
+        state.pacer.set_fps(fps);
+    }
+
+    fn set_image_frame_cb(&mut self, callback: impl FnMut(EmuImageFrame) + Send + 'static) -> EmuCallbackGuard {
+        let id = self.next_cb_registration();
+        *self.image_frame_cb.lock().unwrap() = Some((id, Box::new(callback)));
+
+        let image_frame_cb = self.image_frame_cb.clone();
+        EmuCallbackGuard::new(move || {
+            let mut cb = image_frame_cb.lock().unwrap();
+            if cb.as_ref().map_or(false, |(cur, _)| *cur == id) {
+                *cb = None;
+            }
+        })
+    }
+
+    fn set_sound_frame_cb(&mut self, callback: impl FnMut(EmuSoundFrame) + Send + 'static) -> EmuCallbackGuard {
+        let id = self.next_cb_registration();
+        *self.sound_frame_cb.lock().unwrap() = Some((id, Box::new(callback)));
+
+        let sound_frame_cb = self.sound_frame_cb.clone();
+        EmuCallbackGuard::new(move || {
+            let mut cb = sound_frame_cb.lock().unwrap();
+            if cb.as_ref().map_or(false, |(cur, _)| *cur == id) {
+                *cb = None;
+            }
+        })
+    }
+
+    // only the sample rate applies to the pattern, other options are accepted and ignored
+    fn set_options(&mut self, options: &EmulatorOptions) -> Result<(), String> {
+        if let Some(sample_rate) = options.sample_rate {
+            if sample_rate < REFRESH_RATE as usize {
+                return Err(format!("invalid sample rate: {}", sample_rate));
+            }
+            self.state.lock().unwrap().sample_rate = sample_rate;
+        }
+        Ok(())
+    }
+
+    fn put_input_event(&self, event: EmuInputEvent) {
+        self.inputs.lock().unwrap().put(event);
+    }
+
+    fn set_input_mode(&self, mode: InputMode) {
+        self.inputs.lock().unwrap().set_mode(mode);
+    }
+
+    fn set_turbo(&self, player: u8, button: u8, rate: f64) -> Result<(), String> {
+        self.inputs.lock().unwrap().macros().set_turbo(player, button, rate)
+    }
+
+    fn set_macro(&self, player: u8, name: &str, steps: Vec<MacroStep>) {
+        self.inputs.lock().unwrap().macros().set_macro(player, name, steps);
+    }
+
+    fn run_macro(&self, player: u8, name: &str) -> Result<(), String> {
+        self.inputs.lock().unwrap().macros().run_macro(player, name)
+    }
+
+    fn version(&self) -> String {
+        format!("pattern {}", env!("CARGO_PKG_VERSION"))
+    }
+
+    fn run(&self, system_name: &str) -> EmuRunHandle {
+        println!("running test pattern in place of {}", system_name);
+
+        let emu = self.clone();
+        let stopper = self.clone();
+        self.lifecycle.spawn(move || stopper.stop(), move || emu.run_frames())
+    }
+
+    fn stop(&self) {
+        self.lifecycle.request_stop();
+    }
+
+    fn pause(&self) {
+        self.lifecycle.set_paused(true);
+    }
+
+    fn resume(&self) {
+        self.lifecycle.set_paused(false);
+    }
+
+    fn set_speed(&self, speed: f64) -> Result<(), String> {
+        validate_speed(speed)?;
+        let mut state = self.state.lock().unwrap();
+        state.speed = speed;
+        state.pacer.set_speed(speed);
+        Ok(())
+    }
+
+    fn set_throttled(&self, throttled: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.throttled = throttled;
+        state.pacer.set_throttled(throttled);
+    }
+
+    fn step_frame(&self) -> Result<(), String> {
+        if !self.lifecycle.is_paused() {
+            return Err(format!("frames can only be stepped while paused"));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.step_pending = true;
+        state.pacer.force_next();
+        Ok(())
+    }
+
+    // the frame counter starts over, a hard reset clears the memory as well
+    fn reset(&self, kind: ResetKind) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.frame_no = 0;
+            state.discontinuity = true;
+            if kind == ResetKind::RESET_HARD {
+                state.memory.iter_mut().for_each(|b| *b = 0);
+            }
+        }
+        self.lifecycle.notify(EmuEvent::Reset(kind));
+    }
+
+    fn load_system(&self, system_name: &str) -> Result<(), String> {
+        println!("switching test pattern to {}", system_name);
+        self.rewinder.lock().unwrap().clear();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.frame_no = 0;
+            state.discontinuity = true;
+            state.memory.iter_mut().for_each(|b| *b = 0);
+        }
+        self.lifecycle.notify(EmuEvent::SystemLoaded(String::from(system_name)));
+        Ok(())
+    }
+
+    fn set_rewind(&self, settings: Option<RewindSettings>) -> Result<(), String> {
+        self.rewinder.lock().unwrap().configure(settings)
+    }
+
+    fn rewind(&self, seconds: f64) -> Result<(), String> {
+        let data = self.rewinder.lock().unwrap().rewind(seconds)?;
+        self.load_state_data(&data)?;
+        self.state.lock().unwrap().discontinuity = true;
+        Ok(())
+    }
+
+    fn save_state(&self, slot_name: &str) -> Result<(), String> {
+        validate_slot_name(slot_name)?;
+        let data = self.save_state_data()?;
+        self.state.lock().unwrap().slots.insert(String::from(slot_name), data);
+        Ok(())
+    }
+
+    fn load_state(&self, slot_name: &str) -> Result<(), String> {
+        let data = match self.state.lock().unwrap().slots.get(slot_name) {
+            Some(data) => data.clone(),
+            None => return Err(format!("no state saved in slot {}", slot_name)),
+        };
+        self.load_state_data(&data)
+    }
+
+    fn save_state_data(&self) -> Result<Vec<u8>, String> {
+        Ok(PatternEmulator::state_data(&self.state.lock().unwrap()))
+    }
+
+    fn load_state_data(&self, data: &[u8]) -> Result<(), String> {
+        let frame_no = match data.try_into() {
+            Ok(bytes) => u64::from_le_bytes(bytes),
+            Err(_) => return Err(format!("invalid state data of {} bytes", data.len())),
+        };
+        self.state.lock().unwrap().frame_no = frame_no;
+        Ok(())
+    }
+
+    fn read_memory(&self, location: &MemoryLocation) -> Result<u64, String> {
+        let range = PatternEmulator::memory_range(location)?;
+        let state = self.state.lock().unwrap();
+        Ok(state.memory[range].iter().rev().fold(0u64, |value, b| (value << 8) | u64::from(*b)))
+    }
+
+    fn write_memory(&self, location: &MemoryLocation, value: u64) -> Result<(), String> {
+        let range = PatternEmulator::memory_range(location)?;
+        let mut state = self.state.lock().unwrap();
+        let len = range.len();
+        state.memory[range].copy_from_slice(&value.to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    // any system name is accepted, the pattern needs no roms
+    fn audit_system(&self, system_name: &str) -> Result<RomAudit, String> {
+        Ok(RomAudit {
+            system_name: String::from(system_name),
+            records: vec![],
+        })
+    }
+
+    fn system_info(&self, system_name: &str) -> Result<SystemInfo, String> {
+        let state = self.state.lock().unwrap();
+        Ok(SystemInfo {
+            system_name: String::from(system_name),
+            description: String::from("Test Pattern"),
+            year: String::from("2020"),
+            manufacturer: String::from("Gipan"),
+            screen_width: state.w,
+            screen_height: state.h,
+            orientation: Orientation::ORIENTATION_ROT0,
+            refresh_rate: REFRESH_RATE,
+            players: KEY_CELLS / BUTTONS_PER_PLAYER,
+            buttons: BUTTONS_PER_PLAYER,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn frames_come_out_at_the_size_asked_and_states_round_trip() {
+        let mut emu = PatternEmulator::create(64, 48, 30);
+        let (frame_tx, frame_rx) = mpsc::channel();
+        let _guard = emu.set_image_frame_cb(move |frame| {
+            let _ = frame_tx.send((frame.width, frame.height, frame.stride, frame.buf.len(), frame.frame_number));
+        });
+        let handle = emu.run("test");
+        let next_frame = || frame_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        for _ in 0..3 {
+            let (w, h, stride, len, _) = next_frame();
+            assert_eq!((w, h, stride, len), (64, 48, 64 * BYTES_PER_PIXEL, 64 * 48 * BYTES_PER_PIXEL));
+        }
+
+        emu.pause();
+        // a frame may still be running as it pauses
+        thread::sleep(Duration::from_millis(100));
+        while frame_rx.try_recv().is_ok() {}
+        let saved = emu.save_state_data().unwrap();
+        let saved_frame_number = u64::from_le_bytes(saved[..].try_into().unwrap());
+
+        emu.step_frame().unwrap();
+        assert_eq!(next_frame().4, saved_frame_number + 1);
+        emu.step_frame().unwrap();
+        assert_eq!(next_frame().4, saved_frame_number + 2);
+
+        // the game carries on from the loaded state
+        emu.load_state_data(&saved).unwrap();
+        assert_eq!(emu.save_state_data().unwrap(), saved);
+        emu.step_frame().unwrap();
+        assert_eq!(next_frame().4, saved_frame_number + 1);
+        assert!(emu.load_state_data(&saved[1..]).is_err());
+
+        emu.stop();
+        handle.wait();
+    }
+}
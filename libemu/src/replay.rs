@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use crate::{EmuEvent, EmuInputEvent, InputKind};
+use crate::lifecycle::Lifecycle;
+use crate::macros::InputMacros;
+
+// Replay files are plain text so that they can be attached to bug reports and read by eye.
+//
+//   gipan-replay 1
+//   system dino
+//   resolution 480x320
+//   fps 23
+//   version 0.223 (mame0223)
+//   input <frame number> <kind> <player> <value> <analog> <x> <y>
+//   ...
+//   end <frame number>
+//
+// Every input carries the emulated frame it was applied on. The end line marks the last
+// frame of the session, so that playback runs exactly as long as the recording did.
+const REPLAY_MAGIC: &str = "gipan-replay";
+const REPLAY_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayHeader {
+    pub system_name: String,
+    pub width: usize,
+    pub height: usize,
+    pub fps: usize,
+    pub emulator_version: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayInput {
+    pub frame_number: u64,
+    pub event: EmuInputEvent,
+}
+
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub inputs: Vec<ReplayInput>,
+    pub end_frame_number: Option<u64>,
+}
+
+fn kind_name(kind: InputKind) -> &'static str {
+    match kind {
+        InputKind::INPUT_KEY_DOWN => "key_down",
+        InputKind::INPUT_KEY_UP => "key_up",
+        InputKind::INPUT_BUTTON_DOWN => "button_down",
+        InputKind::INPUT_BUTTON_UP => "button_up",
+        InputKind::INPUT_AXIS_ABSOLUTE => "axis",
+        InputKind::INPUT_AXIS_RELATIVE => "axis_rel",
+        InputKind::INPUT_POINTER_MOVE => "pointer",
+    }
+}
+
+fn parse_kind(name: &str) -> Result<InputKind, String> {
+    match name {
+        "key_down" => Ok(InputKind::INPUT_KEY_DOWN),
+        "key_up" => Ok(InputKind::INPUT_KEY_UP),
+        "button_down" => Ok(InputKind::INPUT_BUTTON_DOWN),
+        "button_up" => Ok(InputKind::INPUT_BUTTON_UP),
+        "axis" => Ok(InputKind::INPUT_AXIS_ABSOLUTE),
+        "axis_rel" => Ok(InputKind::INPUT_AXIS_RELATIVE),
+        "pointer" => Ok(InputKind::INPUT_POINTER_MOVE),
+        _ => Err(format!("unknown input kind: {}", name)),
+    }
+}
+
+fn parse_field<T: std::str::FromStr>(line_no: usize, val: Option<&str>) -> Result<T, String> {
+    val.and_then(|v| v.parse().ok())
+        .ok_or(format!("invalid replay line {}: {:?}", line_no, val))
+}
+
+impl Replay {
+    pub fn load(path: &str) -> Result<Replay, String> {
+        let file = File::open(path).map_err(|e| format!("failed to open replay {}: {}", path, e))?;
+
+        let mut header = ReplayHeader {
+            system_name: String::new(),
+            width: 0,
+            height: 0,
+            fps: 0,
+            emulator_version: String::new(),
+        };
+        let mut inputs = vec![];
+        let mut end_frame_number = None;
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("failed to read replay {}: {}", path, e))?;
+            let line_no = i + 1;
+            let (key, val) = match line.find(' ') {
+                Some(idx) => (&line[..idx], line[idx + 1..].trim()),
+                None => (&line[..], ""),
+            };
+
+            match key {
+                REPLAY_MAGIC => {
+                    let version: u32 = parse_field(line_no, Some(val))?;
+                    if version != REPLAY_VERSION {
+                        return Err(format!("replay version {} is not supported", version));
+                    }
+                },
+                "system" => header.system_name = String::from(val),
+                "resolution" => {
+                    let mut whs = val.split('x');
+                    header.width = parse_field(line_no, whs.next())?;
+                    header.height = parse_field(line_no, whs.next())?;
+                },
+                "fps" => header.fps = parse_field(line_no, Some(val))?,
+                "version" => header.emulator_version = String::from(val),
+                "input" => {
+                    let mut fields = val.split_whitespace();
+                    let frame_number = parse_field(line_no, fields.next())?;
+                    let kind = parse_kind(fields.next().unwrap_or(""))?;
+                    inputs.push(ReplayInput {
+                        frame_number: frame_number,
+                        event: EmuInputEvent {
+                            kind: kind,
+                            player: parse_field(line_no, fields.next())?,
+                            value: parse_field(line_no, fields.next())?,
+                            analog: parse_field(line_no, fields.next())?,
+                            x: parse_field(line_no, fields.next())?,
+                            y: parse_field(line_no, fields.next())?,
+                        },
+                    });
+                },
+                "end" => end_frame_number = Some(parse_field(line_no, Some(val))?),
+                "" => {},
+                _ => return Err(format!("invalid replay line {}: {}", line_no, line)),
+            }
+        }
+
+        if header.system_name.is_empty() {
+            return Err(format!("replay {} has no system", path));
+        }
+
+        Ok(Replay {
+            header: header,
+            inputs: inputs,
+            end_frame_number: end_frame_number,
+        })
+    }
+}
+
+// Writes inputs as they are applied. The end line is written when the writer is dropped.
+pub struct ReplayWriter {
+    out: BufWriter<File>,
+    last_frame_number: u64,
+}
+
+impl ReplayWriter {
+    pub fn create(path: &str, header: &ReplayHeader) -> Result<ReplayWriter, String> {
+        let file = File::create(path).map_err(|e| format!("failed to create replay {}: {}", path, e))?;
+        let mut writer = ReplayWriter {
+            out: BufWriter::new(file),
+            last_frame_number: 0,
+        };
+
+        writer.write_line(&format!("{} {}", REPLAY_MAGIC, REPLAY_VERSION))?;
+        writer.write_line(&format!("system {}", header.system_name))?;
+        writer.write_line(&format!("resolution {}x{}", header.width, header.height))?;
+        writer.write_line(&format!("fps {}", header.fps))?;
+        writer.write_line(&format!("version {}", header.emulator_version))?;
+        Ok(writer)
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), String> {
+        writeln!(self.out, "{}", line).map_err(|e| format!("failed to write replay: {}", e))
+    }
+
+    fn write_input(&mut self, frame_number: u64, event: &EmuInputEvent) -> Result<(), String> {
+        self.write_line(&format!("input {} {} {} {} {} {} {}",
+            frame_number, kind_name(event.kind), event.player, event.value, event.analog, event.x, event.y))?;
+        // keep the file usable even if the process gets killed
+        self.out.flush().map_err(|e| format!("failed to write replay: {}", e))
+    }
+}
+
+impl Drop for ReplayWriter {
+    fn drop(&mut self) {
+        let end = format!("end {}", self.last_frame_number);
+        if let Err(msg) = self.write_line(&end).and_then(|_| {
+            self.out.flush().map_err(|e| format!("failed to write replay: {}", e))
+        }) {
+            println!("{}", msg);
+        }
+    }
+}
+
+pub enum InputMode {
+    // inputs are applied as they come in
+    Live,
+    // inputs are applied as they come in and written to the replay
+    Record(ReplayWriter),
+    // inputs come from the replay only, anything put in live is ignored
+    Playback(Replay),
+}
+
+// Holds inputs until the emulation thread is about to emulate the next frame, so that the
+// frame every input is applied on is known and can be recorded or replayed exactly.
+pub(crate) struct InputScheduler {
+    pending: Vec<EmuInputEvent>,
+    recorder: Option<ReplayWriter>,
+    playback: Option<(VecDeque<ReplayInput>, Option<u64>)>,
+    macros: InputMacros,
+    lifecycle: Lifecycle,
+}
+
+impl InputScheduler {
+    pub fn new(lifecycle: Lifecycle) -> InputScheduler {
+        InputScheduler {
+            pending: vec![],
+            recorder: None,
+            playback: None,
+            macros: InputMacros::new(),
+            lifecycle: lifecycle,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: InputMode) {
+        self.pending.clear();
+        // a previous recording is finished here, when its writer is dropped
+        self.recorder = None;
+        self.playback = None;
+        match mode {
+            InputMode::Live => {},
+            InputMode::Record(writer) => self.recorder = Some(writer),
+            InputMode::Playback(replay) => {
+                self.playback = Some((VecDeque::from(replay.inputs), replay.end_frame_number));
+            },
+        }
+    }
+
+    pub fn macros(&mut self) -> &mut InputMacros {
+        &mut self.macros
+    }
+
+    pub fn put(&mut self, event: EmuInputEvent) {
+        if self.playback.is_none() {
+            self.pending.push(event);
+        }
+    }
+
+    // Called on the emulation thread right before frame_number is emulated.
+    pub fn begin_frame(&mut self, frame_number: u64, mut apply: impl FnMut(&EmuInputEvent)) {
+        if let Some((inputs, end_frame_number)) = self.playback.as_mut() {
+            while inputs.front().map_or(false, |input| input.frame_number <= frame_number) {
+                apply(&inputs.pop_front().unwrap().event);
+            }
+            if inputs.is_empty() && end_frame_number.map_or(true, |end| frame_number >= end) {
+                self.playback = None;
+                self.lifecycle.notify(EmuEvent::ReplayFinished);
+            }
+            return;
+        }
+
+        // turbo and macros are recorded as the inputs they expand to
+        let events = self.macros.expand(frame_number, self.pending.drain(..).collect());
+        for event in events.iter() {
+            if let Some(recorder) = self.recorder.as_mut() {
+                if let Err(msg) = recorder.write_input(frame_number, event) {
+                    println!("{}", msg);
+                }
+            }
+            apply(event);
+        }
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.last_frame_number = frame_number;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn recorded_inputs_are_read_back() {
+        let path = env::temp_dir().join(format!("libemu-replay-{}.replay", process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let header = ReplayHeader {
+            system_name: String::from("dino"),
+            width: 480,
+            height: 320,
+            fps: 23,
+            emulator_version: String::from("0.223 (mame0223)"),
+        };
+
+        let mut scheduler = InputScheduler::new(Lifecycle::default());
+        scheduler.set_mode(InputMode::Record(ReplayWriter::create(&path, &header).unwrap()));
+        let recorded = vec![
+            (3, EmuInputEvent::button(0, 2, InputKind::INPUT_BUTTON_DOWN)),
+            (3, EmuInputEvent::axis(1, 0, InputKind::INPUT_AXIS_ABSOLUTE, -1200)),
+            (7, EmuInputEvent::button(0, 2, InputKind::INPUT_BUTTON_UP)),
+            (9, EmuInputEvent { player: 0, value: 0, kind: InputKind::INPUT_POINTER_MOVE, analog: 0, x: 120, y: -4 }),
+        ];
+        for frame_number in 0..12 {
+            for (_, event) in recorded.iter().filter(|(n, _)| *n == frame_number) {
+                scheduler.put(event.clone());
+            }
+            scheduler.begin_frame(frame_number, |_| {});
+        }
+        // the end line is written as the recording finishes
+        scheduler.set_mode(InputMode::Live);
+
+        let replay = Replay::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(replay.header, header);
+        assert_eq!(replay.end_frame_number, Some(11));
+        let inputs: Vec<_> = replay.inputs.iter()
+            .map(|input| (input.frame_number, format!("{:?}", input.event)))
+            .collect();
+        let expected: Vec<_> = recorded.iter()
+            .map(|(frame_number, event)| (*frame_number, format!("{:?}", event)))
+            .collect();
+        assert_eq!(inputs, expected);
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+pub const DEFAULT_REWIND_INTERVAL: u32 = 30;
+pub const DEFAULT_REWIND_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+// refresh rate assumed until the emulator reports the one of the running system
+const DEFAULT_REFRESH_RATE: f64 = 60.0;
+// snapshots waiting to be compressed, any more are skipped
+const PENDING_SNAPSHOTS: usize = 2;
+
+// A snapshot is taken every interval emulated frames. The oldest snapshots are dropped
+// once the compressed ones take more than memory_limit bytes.
+#[derive(Debug, Copy, Clone)]
+pub struct RewindSettings {
+    pub interval: u32,
+    pub memory_limit: usize,
+}
+
+impl Default for RewindSettings {
+    fn default() -> RewindSettings {
+        RewindSettings {
+            interval: DEFAULT_REWIND_INTERVAL,
+            memory_limit: DEFAULT_REWIND_MEMORY_LIMIT,
+        }
+    }
+}
+
+// tick is the number of frames emulated since rewind was turned on, rewinds included
+struct Snapshot {
+    tick: u64,
+    data: Vec<u8>,
+}
+
+// generation changes whenever the snapshots are cut short, so that snapshots still being
+// compressed by then are not added to a timeline they are no longer part of
+struct SnapshotRing {
+    snapshots: VecDeque<Snapshot>,
+    size: usize,
+    memory_limit: usize,
+    generation: u64,
+}
+
+impl SnapshotRing {
+    fn push(&mut self, snapshot: Snapshot) {
+        self.size += snapshot.data.len();
+        self.snapshots.push_back(snapshot);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.size > self.memory_limit {
+            match self.snapshots.pop_front() {
+                Some(oldest) => self.size -= oldest.data.len(),
+                None => break,
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.snapshots.clear();
+        self.size = 0;
+        self.generation += 1;
+    }
+}
+
+// Ring of compressed save states taken on the emulation thread, to go back in time from.
+// Snapshots are compressed on a thread of their own so that emulation isn't held up.
+pub(crate) struct Rewinder {
+    settings: Option<RewindSettings>,
+    refresh_rate: f64,
+    tick: u64,
+    last_snapshot_tick: Option<u64>,
+    ring: Arc<Mutex<SnapshotRing>>,
+    snapshot_tx: Option<mpsc::SyncSender<(u64, Snapshot)>>,
+}
+
+impl Rewinder {
+    pub fn new() -> Rewinder {
+        Rewinder {
+            settings: None,
+            refresh_rate: DEFAULT_REFRESH_RATE,
+            tick: 0,
+            last_snapshot_tick: None,
+            ring: Arc::new(Mutex::new(SnapshotRing {
+                snapshots: VecDeque::new(),
+                size: 0,
+                memory_limit: DEFAULT_REWIND_MEMORY_LIMIT,
+                generation: 0,
+            })),
+            snapshot_tx: None,
+        }
+    }
+
+    // None turns rewind off and frees the snapshots
+    pub fn configure(&mut self, settings: Option<RewindSettings>) -> Result<(), String> {
+        let settings = match settings {
+            Some(settings) if settings.interval == 0 || settings.memory_limit == 0 => {
+                return Err(format!("invalid rewind settings: {:?}", settings));
+            },
+            Some(settings) => settings,
+            None => {
+                self.settings = None;
+                self.snapshot_tx = None;
+                self.clear();
+                return Ok(());
+            },
+        };
+
+        {
+            let mut ring = self.ring.lock().unwrap();
+            ring.memory_limit = settings.memory_limit;
+            ring.trim();
+        }
+        if self.snapshot_tx.is_none() {
+            let (snapshot_tx, snapshot_rx) = mpsc::sync_channel(PENDING_SNAPSHOTS);
+            run_compressor(self.ring.clone(), snapshot_rx);
+            self.snapshot_tx = Some(snapshot_tx);
+        }
+        self.settings = Some(settings);
+        Ok(())
+    }
+
+    pub fn set_refresh_rate(&mut self, refresh_rate: f64) {
+        if refresh_rate > 0.0 {
+            self.refresh_rate = refresh_rate;
+        }
+    }
+
+    // Drops every snapshot, ex, when another system is loaded
+    pub fn clear(&mut self) {
+        self.ring.lock().unwrap().clear();
+        self.last_snapshot_tick = None;
+    }
+
+    // Called on the emulation thread right before a frame is emulated, snapshot saving the state
+    pub fn frame_begin(&mut self, snapshot: impl FnOnce() -> Result<Vec<u8>, String>) {
+        let (interval, snapshot_tx) = match (self.settings, self.snapshot_tx.as_ref()) {
+            (Some(settings), Some(snapshot_tx)) => (settings.interval as u64, snapshot_tx),
+            _ => return,
+        };
+
+        self.tick += 1;
+        if self.last_snapshot_tick.map_or(false, |last| self.tick - last < interval) {
+            return;
+        }
+        self.last_snapshot_tick = Some(self.tick);
+
+        // the state isn't available until the system has started
+        if let Ok(data) = snapshot() {
+            let generation = self.ring.lock().unwrap().generation;
+            let _ = snapshot_tx.try_send((generation, Snapshot {
+                tick: self.tick,
+                data: data,
+            }));
+        }
+    }
+
+    // Returns the state to go back to, the latest snapshot at least seconds old or the oldest
+    // one if none goes back that far. Snapshots after it are dropped.
+    pub fn rewind(&mut self, seconds: f64) -> Result<Vec<u8>, String> {
+        if self.settings.is_none() {
+            return Err(format!("rewind is off"));
+        }
+        if !(seconds > 0.0) {
+            return Err(format!("invalid rewind seconds: {}", seconds));
+        }
+
+        let target_tick = self.tick.saturating_sub((seconds * self.refresh_rate).round() as u64);
+        let mut ring = self.ring.lock().unwrap();
+        let idx = match ring.snapshots.iter().rposition(|snapshot| snapshot.tick <= target_tick) {
+            Some(idx) => idx,
+            None if !ring.snapshots.is_empty() => 0,
+            None => return Err(format!("no snapshot to rewind to")),
+        };
+
+        let dropped: usize = ring.snapshots.iter().skip(idx + 1).map(|snapshot| snapshot.data.len()).sum();
+        ring.snapshots.truncate(idx + 1);
+        ring.size -= dropped;
+        ring.generation += 1;
+
+        let snapshot = &ring.snapshots[idx];
+        self.tick = snapshot.tick;
+        self.last_snapshot_tick = Some(snapshot.tick);
+        decompress(&snapshot.data)
+    }
+}
+
+fn run_compressor(ring: Arc<Mutex<SnapshotRing>>, snapshot_rx: mpsc::Receiver<(u64, Snapshot)>) {
+    thread::spawn(move || {
+        // ends once rewind is turned off or the emulator is gone
+        for (generation, snapshot) in snapshot_rx.iter() {
+            let data = match compress(&snapshot.data) {
+                Ok(data) => data,
+                Err(msg) => {
+                    println!("{}", msg);
+                    continue;
+                },
+            };
+
+            let mut ring = ring.lock().unwrap();
+            if ring.generation == generation {
+                ring.push(Snapshot {
+                    tick: snapshot.tick,
+                    data: data,
+                });
+            }
+        }
+    });
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut encoder = ZlibEncoder::new(Vec::with_capacity(data.len() / 2), Compression::fast());
+    encoder.write_all(data)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| format!("failed to compress snapshot: {}", e))
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut state = vec![];
+    ZlibDecoder::new(data).read_to_end(&mut state)
+        .map(|_| state)
+        .map_err(|e| format!("failed to decompress snapshot: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn state(tick: u64) -> Vec<u8> {
+        let mut data = tick.to_le_bytes().to_vec();
+        data.resize(4096, tick as u8);
+        data
+    }
+
+    fn ticks(rewinder: &Rewinder) -> Vec<u64> {
+        rewinder.ring.lock().unwrap().snapshots.iter().map(|snapshot| snapshot.tick).collect()
+    }
+
+    // emulates frames, waiting for every snapshot to be compressed so that none is skipped
+    fn run_frames(rewinder: &mut Rewinder, frames: u64) {
+        for _ in 0..frames {
+            let mut taken = None;
+            let tick = rewinder.tick + 1;
+            rewinder.frame_begin(|| {
+                taken = Some(tick);
+                Ok(state(tick))
+            });
+            if let Some(tick) = taken {
+                let start = Instant::now();
+                while ticks(rewinder).last() != Some(&tick) {
+                    assert!(start.elapsed() < Duration::from_secs(5), "snapshot of tick {} wasn't kept", tick);
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rewinding_goes_back_to_the_latest_snapshot_old_enough() {
+        let mut rewinder = Rewinder::new();
+        assert!(rewinder.rewind(1.0).is_err());
+        rewinder.configure(Some(RewindSettings { interval: 10, memory_limit: DEFAULT_REWIND_MEMORY_LIMIT })).unwrap();
+        rewinder.set_refresh_rate(60.0);
+        run_frames(&mut rewinder, 300);
+        assert_eq!(ticks(&rewinder), (1..300).step_by(10).collect::<Vec<_>>());
+
+        // 2 seconds before tick 300 is tick 180, the snapshot before it was taken at 171
+        assert_eq!(rewinder.rewind(2.0).unwrap(), state(171));
+        assert_eq!(ticks(&rewinder).last(), Some(&171));
+        // the clock carries on from there
+        run_frames(&mut rewinder, 10);
+        assert_eq!(ticks(&rewinder).last(), Some(&181));
+
+        // further back than the snapshots go is the oldest one
+        assert_eq!(rewinder.rewind(100.0).unwrap(), state(1));
+        assert_eq!(ticks(&rewinder), vec![1]);
+        assert!(rewinder.rewind(0.0).is_err());
+    }
+
+    #[test]
+    fn the_oldest_snapshots_are_dropped_past_the_memory_limit() {
+        let snapshot_size = compress(&state(1)).unwrap().len();
+        let mut rewinder = Rewinder::new();
+        rewinder.configure(Some(RewindSettings { interval: 1, memory_limit: snapshot_size * 7 / 2 })).unwrap();
+        run_frames(&mut rewinder, 10);
+
+        // room for 3 and a half snapshots
+        assert_eq!(ticks(&rewinder), vec![8, 9, 10]);
+        let ring = rewinder.ring.lock().unwrap();
+        assert!(ring.size <= ring.memory_limit);
+        assert_eq!(ring.size, ring.snapshots.iter().map(|snapshot| snapshot.data.len()).sum::<usize>());
+    }
+
+    #[test]
+    fn cleared_snapshots_are_gone() {
+        let mut rewinder = Rewinder::new();
+        rewinder.configure(Some(RewindSettings { interval: 10, memory_limit: DEFAULT_REWIND_MEMORY_LIMIT })).unwrap();
+        run_frames(&mut rewinder, 25);
+        assert_eq!(ticks(&rewinder), vec![1, 11, 21]);
+
+        rewinder.clear();
+        assert!(ticks(&rewinder).is_empty());
+        assert!(rewinder.rewind(1.0).is_err());
+        // the next frame is snapshot right away
+        run_frames(&mut rewinder, 1);
+        assert_eq!(ticks(&rewinder), vec![26]);
+        assert_eq!(rewinder.rewind(10.0).unwrap(), state(26));
+    }
+}
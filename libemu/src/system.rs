@@ -0,0 +1,61 @@
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RomStatus {
+    ROM_STATUS_GOOD,
+    ROM_STATUS_NOT_FOUND,
+    ROM_STATUS_BAD_SIZE,
+    ROM_STATUS_BAD_CHECKSUM,
+    // the rom has never been dumped, so the system is expected to run without it
+    ROM_STATUS_NO_DUMP,
+}
+
+#[derive(Debug, Clone)]
+pub struct RomAuditRecord {
+    pub name: String,
+    pub status: RomStatus,
+    pub expected_size: u64,
+    pub actual_size: u64,
+    pub optional: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RomAudit {
+    pub system_name: String,
+    pub records: Vec<RomAuditRecord>,
+}
+
+impl RomAudit {
+    pub fn is_playable(&self) -> bool {
+        self.records.iter().all(|r| {
+            r.optional || r.status == RomStatus::ROM_STATUS_GOOD || r.status == RomStatus::ROM_STATUS_NO_DUMP
+        })
+    }
+
+    pub fn problems(&self) -> Vec<&RomAuditRecord> {
+        self.records.iter()
+            .filter(|r| r.status != RomStatus::ROM_STATUS_GOOD && r.status != RomStatus::ROM_STATUS_NO_DUMP)
+            .collect()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Orientation {
+    ORIENTATION_ROT0,
+    ORIENTATION_ROT90,
+    ORIENTATION_ROT180,
+    ORIENTATION_ROT270,
+}
+
+// screen size is the native size of the system, before any scaling to the image frame size
+#[derive(Debug, Clone)]
+pub struct SystemInfo {
+    pub system_name: String,
+    pub description: String,
+    pub year: String,
+    pub manufacturer: String,
+    pub screen_width: usize,
+    pub screen_height: usize,
+    pub orientation: Orientation,
+    pub refresh_rate: f64,
+    pub players: usize,
+    pub buttons: usize,
+}
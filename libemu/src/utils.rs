@@ -5,3 +5,371 @@ pub mod time {
         SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
     }
 }
+
+pub mod pacing {
+    use std::time::{Duration, Instant};
+
+    use libbuf::{BufferPool, PooledBuffer};
+
+    // how far sound may stray from the video before it is corrected, a frame either way being normal
+    const DRIFT_TOLERANCE: f64 = 0.04;
+    // further than this, sound starts over from the video clock rather than catching up
+    const DRIFT_RESYNC: f64 = 0.5;
+    // at most 1 sample in this many is dropped or duplicated, which goes unheard
+    const CORRECTION_RATIO: usize = 200;
+    // seconds of sound between drift reports
+    const DRIFT_REPORT_INTERVAL: f64 = 10.0;
+
+    // Decimates emulated frames down to the output fps. Emulated frames are laid out on a
+    // presentation clock, which follows the emulated clock scaled by the speed multiplier
+    // (or the wall clock when unthrottled). A frame is picked when it is the first one
+    // falling into a new output slot of 1/fps presentation seconds, so picked frames stay
+    // evenly spaced whatever the emulation speed (e.g. 23 out of every 59.63 frames at 1x).
+    pub struct FramePacer {
+        fps: usize,
+        speed: f64,
+        throttled: bool,
+        resync: bool,
+        force_next: bool,
+        last_frame_number: Option<u64>,
+        last_instant: Instant,
+        presentation_time: f64,
+        last_slot: u64,
+        // bumped whenever the presentation clock starts over
+        epoch: u64,
+    }
+
+    impl FramePacer {
+        pub fn new(fps: usize) -> FramePacer {
+            FramePacer {
+                fps: fps,
+                speed: 1.0,
+                throttled: true,
+                resync: true,
+                force_next: false,
+                last_frame_number: None,
+                last_instant: Instant::now(),
+                presentation_time: 0.0,
+                last_slot: 0,
+                epoch: 0,
+            }
+        }
+
+        pub fn set_fps(&mut self, fps: usize) {
+            self.fps = fps;
+            self.resync = true;
+        }
+
+        pub fn set_speed(&mut self, speed: f64) {
+            self.speed = speed;
+        }
+
+        pub fn set_throttled(&mut self, throttled: bool) {
+            self.throttled = throttled;
+        }
+
+        // sound only lines up with the output at native speed
+        pub fn is_realtime(&self) -> bool {
+            self.throttled && self.speed == 1.0
+        }
+
+        // Makes sure the next new frame is picked, e.g. a single stepped frame which
+        // wouldn't reach the next output slot on its own.
+        pub fn force_next(&mut self) {
+            self.force_next = true;
+        }
+
+        // Returns the presentation timestamp of the output slot if the frame should be kept.
+        pub fn pick(&mut self, frame_number: u64, refresh_rate: f64) -> Option<Duration> {
+            let refresh_rate = if refresh_rate > 0.0 { refresh_rate } else { self.fps as f64 };
+            let now = Instant::now();
+            let elapsed = (now - self.last_instant).as_secs_f64();
+            self.last_instant = now;
+
+            match self.last_frame_number {
+                Some(last) if frame_number == last => return None,
+                Some(last) if frame_number > last => {
+                    self.presentation_time += if self.throttled {
+                        (frame_number - last) as f64 / refresh_rate / self.speed
+                    } else {
+                        elapsed
+                    };
+                },
+                // first frame, or the frame counter went backwards (reset or state load), start over
+                _ => {
+                    self.presentation_time = frame_number as f64 / refresh_rate;
+                    self.resync = true;
+                    self.epoch += 1;
+                },
+            }
+            self.last_frame_number = Some(frame_number);
+
+            let slot = (self.presentation_time * self.fps as f64).floor() as u64;
+            if self.resync || slot > self.last_slot {
+                self.last_slot = slot;
+            } else if self.force_next {
+                // the forced slot may lie ahead of the clock, which then carries on from there
+                self.last_slot += 1;
+                self.presentation_time = self.presentation_time.max(self.last_slot as f64 / self.fps as f64);
+            } else {
+                return None;
+            }
+            self.resync = false;
+            self.force_next = false;
+
+            Some(Duration::from_secs_f64(self.last_slot as f64 / self.fps as f64))
+        }
+
+        // presentation time of the latest emulated frame, along with the epoch of the clock
+        pub fn clock(&self) -> (u64, f64) {
+            (self.epoch, self.presentation_time)
+        }
+    }
+
+    pub struct SoundTiming {
+        pub emu_timestamp: Duration,
+        // sample frames to duplicate (> 0) or drop (< 0) to stay in step with the video
+        pub correction: isize,
+    }
+
+    // Lays sound frames out on the presentation clock of the FramePacer by counting samples,
+    // so sound timestamps advance by exactly samples / sample_rate rather than with the wall
+    // clock. Sound may still drift from the video, ex, when the system's sound and video clocks
+    // don't divide evenly, so the offset between the two is watched and sound is stretched or
+    // shrunk by a sample now and then once it's off by more than DRIFT_TOLERANCE.
+    pub struct SoundClock {
+        epoch: Option<u64>,
+        sample_rate: usize,
+        origin: f64,
+        samples: u64,
+        dropped: usize,
+        duplicated: usize,
+        last_report: f64,
+    }
+
+    impl SoundClock {
+        pub fn new() -> SoundClock {
+            SoundClock {
+                epoch: None,
+                sample_rate: 0,
+                origin: 0.0,
+                samples: 0,
+                dropped: 0,
+                duplicated: 0,
+                last_report: 0.0,
+            }
+        }
+
+        // Places a sound frame emulated along with the latest frame seen by pacer
+        pub fn place(&mut self, pacer: &FramePacer, samples: usize, sample_rate: usize) -> SoundTiming {
+            let (epoch, video_time) = pacer.clock();
+            let sound_time = self.origin + self.samples as f64 / sample_rate as f64;
+            let offset = sound_time - video_time;
+            if self.epoch != Some(epoch) || self.sample_rate != sample_rate || offset.abs() > DRIFT_RESYNC {
+                if self.epoch == Some(epoch) && self.sample_rate == sample_rate {
+                    println!("sound is {:+.1} ms off the video, starting it over", offset * 1000.0);
+                }
+                self.epoch = Some(epoch);
+                self.sample_rate = sample_rate;
+                self.origin = video_time;
+                self.samples = samples as u64;
+                self.last_report = video_time;
+                return SoundTiming {
+                    emu_timestamp: Duration::from_secs_f64(video_time.max(0.0)),
+                    correction: 0,
+                };
+            }
+
+            let correction = if offset.abs() > DRIFT_TOLERANCE {
+                let wanted = (offset.abs() * sample_rate as f64).round() as usize;
+                let most = (samples / CORRECTION_RATIO).max(1).min(samples);
+                let amount = wanted.min(most);
+                if offset > 0.0 {
+                    self.dropped += amount;
+                    -(amount as isize)
+                } else {
+                    self.duplicated += amount;
+                    amount as isize
+                }
+            } else {
+                0
+            };
+            self.samples += (samples as isize + correction) as u64;
+
+            if sound_time - self.last_report >= DRIFT_REPORT_INTERVAL {
+                if self.dropped > 0 || self.duplicated > 0 {
+                    println!("sound is {:+.1} ms off the video, {} samples dropped and {} duplicated",
+                        offset * 1000.0, self.dropped, self.duplicated);
+                }
+                self.dropped = 0;
+                self.duplicated = 0;
+                self.last_report = sound_time;
+            }
+
+            SoundTiming {
+                emu_timestamp: Duration::from_secs_f64(sound_time.max(0.0)),
+                correction: correction,
+            }
+        }
+
+        // Sped up or slowed down sound can't be streamed in realtime, so silence stands in for it.
+        // Returns the timestamp and the number of sample frames of silence reaching up to the video
+        // clock, if any, which keeps the sound track going without a gap until the speed is back to 1x.
+        pub fn place_silence(&mut self, pacer: &FramePacer, sample_rate: usize) -> Option<(Duration, usize)> {
+            let (epoch, video_time) = pacer.clock();
+            let sound_time = self.origin + self.samples as f64 / sample_rate as f64;
+            if self.epoch != Some(epoch) || self.sample_rate != sample_rate || video_time - sound_time > DRIFT_RESYNC {
+                self.epoch = Some(epoch);
+                self.sample_rate = sample_rate;
+                self.origin = video_time;
+                self.samples = 0;
+                self.last_report = video_time;
+                return None;
+            }
+
+            let wanted = ((video_time - self.origin) * sample_rate as f64).round() as u64;
+            if wanted <= self.samples {
+                return None;
+            }
+            let samples = (wanted - self.samples) as usize;
+            self.samples = wanted;
+            Some((Duration::from_secs_f64(sound_time.max(0.0)), samples))
+        }
+    }
+
+    // Interleaved silence of samples sample frames
+    pub fn silence(pool: &BufferPool<i16>, samples: usize, channels: usize) -> PooledBuffer<i16> {
+        let mut buf = pool.acquire(samples * channels);
+        // a reused buffer keeps what it had
+        for sample in buf.iter_mut() {
+            *sample = 0;
+        }
+        buf
+    }
+
+    // Copies interleaved samples, dropping or duplicating correction sample frames spread evenly
+    pub fn correct_samples(pool: &BufferPool<i16>, src: &[i16], channels: usize, correction: isize) -> PooledBuffer<i16> {
+        let frames = src.len() / channels;
+        let amount = (correction.abs() as usize).min(frames);
+        if amount == 0 {
+            return pool.acquire_from(src);
+        }
+
+        let out_frames = if correction > 0 { frames + amount } else { frames - amount };
+        let mut buf = pool.acquire(out_frames * channels);
+        let mut next_mark = 0;
+        let mut out = 0;
+        for frame in 0..frames {
+            // frames at the middle of each of amount equal stretches are the ones corrected
+            let marked = next_mark < amount && frame == (2 * next_mark + 1) * frames / (2 * amount);
+            if marked {
+                next_mark += 1;
+            }
+            let copies = match (marked, correction > 0) {
+                (true, true) => 2,
+                (true, false) => 0,
+                _ => 1,
+            };
+            for _ in 0..copies {
+                buf[out * channels..(out + 1) * channels]
+                    .copy_from_slice(&src[frame * channels..(frame + 1) * channels]);
+                out += 1;
+            }
+        }
+        buf
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn decimated_frames_fall_into_evenly_spaced_slots() {
+            let mut pacer = FramePacer::new(23);
+            let picked: Vec<Duration> = (1..=600).filter_map(|frame_number| pacer.pick(frame_number, 59.63)).collect();
+            // 600 frames last 10.06 seconds, every slot of 1/23 seconds gets a single frame
+            assert_eq!(picked.len(), 232);
+            for (slot, timestamp) in picked.iter().enumerate() {
+                assert_eq!(*timestamp, Duration::from_secs_f64(slot as f64 / 23.0));
+            }
+        }
+
+        #[test]
+        fn a_repeated_frame_is_not_picked() {
+            let mut pacer = FramePacer::new(60);
+            assert!(pacer.pick(1, 60.0).is_some());
+            assert!(pacer.pick(1, 60.0).is_none());
+            assert!(pacer.pick(2, 60.0).is_some());
+        }
+
+        #[test]
+        fn a_frame_counter_going_backwards_starts_the_clock_over() {
+            let mut pacer = FramePacer::new(30);
+            for frame_number in 100..=120 {
+                pacer.pick(frame_number, 60.0);
+            }
+            let (epoch, _) = pacer.clock();
+
+            // ex, a state saved at frame 30 is loaded, the first frame afterwards is picked
+            assert_eq!(pacer.pick(30, 60.0), Some(Duration::from_secs_f64(0.5)));
+            assert_eq!(pacer.clock(), (epoch + 1, 0.5));
+            assert!(pacer.pick(31, 60.0).is_none());
+            assert_eq!(pacer.pick(32, 60.0), Some(Duration::from_secs_f64(16.0 / 30.0)));
+        }
+
+        #[test]
+        fn a_forced_frame_is_picked_in_the_next_slot() {
+            let mut pacer = FramePacer::new(20);
+            assert_eq!(pacer.pick(60, 60.0), Some(Duration::from_secs(1)));
+            assert!(pacer.pick(61, 60.0).is_none());
+
+            pacer.force_next();
+            assert_eq!(pacer.pick(62, 60.0), Some(Duration::from_secs_f64(21.0 / 20.0)));
+            // the clock carries on from the forced slot
+            assert!(pacer.pick(63, 60.0).is_none());
+            assert_eq!(pacer.pick(66, 60.0), Some(Duration::from_secs_f64(22.0 / 20.0)));
+        }
+
+        #[test]
+        fn samples_are_dropped_or_duplicated_by_frame() {
+            let pool = BufferPool::create(1);
+            let src = [1, -1, 2, -2, 3, -3, 4, -4];
+            assert_eq!(&*correct_samples(&pool, &src, 2, 0), &src[..]);
+            assert_eq!(&*correct_samples(&pool, &src, 2, -1), &[1, -1, 2, -2, 4, -4][..]);
+            assert_eq!(&*correct_samples(&pool, &src, 2, 1), &[1, -1, 2, -2, 3, -3, 3, -3, 4, -4][..]);
+        }
+
+        #[test]
+        fn sound_starts_over_when_the_video_clock_does() {
+            let mut pacer = FramePacer::new(60);
+            let mut clock = SoundClock::new();
+            pacer.pick(120, 60.0);
+            assert_eq!(clock.place(&pacer, 800, 48000).emu_timestamp, Duration::from_secs(2));
+            pacer.pick(121, 60.0);
+            assert_eq!(clock.place(&pacer, 800, 48000).emu_timestamp, Duration::from_secs_f64(2.0 + 1.0 / 60.0));
+
+            // ex, a state load
+            pacer.pick(30, 60.0);
+            let timing = clock.place(&pacer, 800, 48000);
+            assert_eq!(timing.emu_timestamp, Duration::from_secs_f64(0.5));
+            assert_eq!(timing.correction, 0);
+        }
+
+        #[test]
+        fn drifting_sound_is_corrected_a_little_at_a_time() {
+            let mut pacer = FramePacer::new(60);
+            let mut clock = SoundClock::new();
+            pacer.pick(60, 60.0);
+            // sound of frames the video clock didn't see runs ahead of it
+            let corrections: Vec<isize> = (0..5).map(|_| clock.place(&pacer, 800, 48000).correction).collect();
+            // 50 ms ahead on the 4th frame, of which at most 1 sample in 200 is dropped
+            assert_eq!(corrections, vec![0, 0, 0, -4, -4]);
+
+            // and falls behind when the video moves on without it
+            for frame_number in 61..=70 {
+                pacer.pick(frame_number, 60.0);
+            }
+            assert_eq!(clock.place(&pacer, 800, 48000).correction, 4);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// Helpers of the tests running the libretro core of tests/core, which is built once per test
+// process. A process loads a single libretro core, so every test file creates one emulator.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libemu::{AddressSpace, Emulator, EmulatorOptions, LibretroEmulator, MemoryLocation};
+
+// pixel formats of libretro.h
+pub const FORMAT_0RGB1555: u8 = 0;
+pub const FORMAT_XRGB8888: u8 = 1;
+pub const FORMAT_RGB565: u8 = 2;
+
+// what the core keeps in its system ram, see test_core.c
+pub const RAM_FRAMES: u64 = 0;
+pub const RAM_JOYPADS: u64 = 4;
+pub const RAM_BUTTON_A: u64 = 12;
+pub const RAM_ANALOG: u64 = 16;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static BUILD_CORE: Once = Once::new();
+
+// Builds the core with the C compiler in CC, cc by default
+fn test_core() -> PathBuf {
+    let out = env::temp_dir().join(format!("libemu-test-core-{}.so", process::id()));
+    BUILD_CORE.call_once(|| {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/core/test_core.c");
+        let compiler = env::var("CC").unwrap_or_else(|_| String::from("cc"));
+        let status = Command::new(&compiler)
+            .args(&["-shared", "-fPIC", "-o"])
+            .arg(&out)
+            .arg(&src)
+            .status()
+            .unwrap_or_else(|e| panic!("failed to run {}: {}", compiler, e));
+        assert!(status.success(), "failed to build {:?}", src);
+    });
+    out
+}
+
+// A directory of its own for the content and states of a test
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("libemu-{}-{}", name, process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Writes the content of the core, a picture of w x h pixels whose rows are pitch bytes apart
+pub fn write_picture(dir: &Path, name: &str, format: u8, w: u8, h: u8, pitch: u8, pixels: &[u8]) {
+    let mut content = vec![format, w, h, pitch];
+    content.extend_from_slice(pixels);
+    fs::write(dir.join(format!("{}.pic", name)), content).unwrap();
+}
+
+// The emulator runs the core at the size of its pictures, so that they aren't scaled
+pub fn create_emulator(dir: &Path, w: usize, h: usize) -> impl Emulator {
+    let mut emu = LibretroEmulator::create(&test_core().to_string_lossy(), w, h, 60).unwrap();
+    emu.set_options(&EmulatorOptions {
+        rompath: Some(dir.to_string_lossy().into_owned()),
+        state_directory: Some(dir.join("sta").to_string_lossy().into_owned()),
+        ..Default::default()
+    }).unwrap();
+    emu
+}
+
+pub fn read_ram(emu: &impl Emulator, address: u64, width: usize) -> u64 {
+    emu.read_memory(&MemoryLocation {
+        cpu_tag: String::from(":maincpu"),
+        space: AddressSpace::ADDRESS_SPACE_PROGRAM,
+        address: address,
+        width: width,
+    }).unwrap()
+}
+
+pub fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        if start.elapsed() > TIMEOUT {
+            panic!("timed out waiting for {}", what);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
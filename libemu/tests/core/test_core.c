@@ -0,0 +1,199 @@
+// A libretro core for the tests of LibretroEmulator, built by tests/common.
+//
+// The content is a picture: a header of 4 bytes (pixel format, width, height, pitch)
+// followed by pitch * height bytes of pixels, which the core puts out every frame.
+// What the core reads of its inputs is kept in its system ram, which is also what it
+// serializes, see the RAM_* offsets below.
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <string.h>
+
+#define RETRO_DEVICE_JOYPAD 1
+#define RETRO_DEVICE_ANALOG 5
+#define RETRO_DEVICE_ID_JOYPAD_A 8
+#define RETRO_DEVICE_ID_JOYPAD_MASK 256
+#define RETRO_MEMORY_SYSTEM_RAM 2
+#define RETRO_ENVIRONMENT_SET_PIXEL_FORMAT 10
+
+#define PLAYERS 4
+#define HEADER_SIZE 4
+#define MAX_PICTURE_SIZE 4096
+
+// frame counter, u32
+#define RAM_FRAMES 0
+// joypad of every player as a bit mask, u16 each
+#define RAM_JOYPADS 4
+// A button of player 1 read on its own, u8
+#define RAM_BUTTON_A 12
+// left x, left y, right x, right y of player 1, i16 each
+#define RAM_ANALOG 16
+#define RAM_SIZE 64
+
+typedef bool (*retro_environment_t)(unsigned cmd, void *data);
+typedef void (*retro_video_refresh_t)(const void *data, unsigned width, unsigned height, size_t pitch);
+typedef void (*retro_audio_sample_t)(int16_t left, int16_t right);
+typedef size_t (*retro_audio_sample_batch_t)(const int16_t *data, size_t frames);
+typedef void (*retro_input_poll_t)(void);
+typedef int16_t (*retro_input_state_t)(unsigned port, unsigned device, unsigned index, unsigned id);
+
+struct retro_system_info {
+    const char *library_name;
+    const char *library_version;
+    const char *valid_extensions;
+    bool need_fullpath;
+    bool block_extract;
+};
+
+struct retro_game_geometry {
+    unsigned base_width;
+    unsigned base_height;
+    unsigned max_width;
+    unsigned max_height;
+    float aspect_ratio;
+};
+
+struct retro_system_timing {
+    double fps;
+    double sample_rate;
+};
+
+struct retro_system_av_info {
+    struct retro_game_geometry geometry;
+    struct retro_system_timing timing;
+};
+
+struct retro_game_info {
+    const char *path;
+    const void *data;
+    size_t size;
+    const char *meta;
+};
+
+static retro_environment_t environment;
+static retro_video_refresh_t video_refresh;
+static retro_input_poll_t input_poll;
+static retro_input_state_t input_state;
+
+static uint8_t ram[RAM_SIZE];
+static uint8_t picture[MAX_PICTURE_SIZE];
+static unsigned picture_width, picture_height, picture_pitch;
+
+static void put_u16(size_t offset, uint16_t value) {
+    memcpy(ram + offset, &value, sizeof value);
+}
+
+void retro_set_environment(retro_environment_t cb) { environment = cb; }
+void retro_set_video_refresh(retro_video_refresh_t cb) { video_refresh = cb; }
+void retro_set_audio_sample(retro_audio_sample_t cb) { (void)cb; }
+void retro_set_audio_sample_batch(retro_audio_sample_batch_t cb) { (void)cb; }
+void retro_set_input_poll(retro_input_poll_t cb) { input_poll = cb; }
+void retro_set_input_state(retro_input_state_t cb) { input_state = cb; }
+
+void retro_init(void) {}
+void retro_deinit(void) {}
+unsigned retro_api_version(void) { return 1; }
+
+void retro_get_system_info(struct retro_system_info *info) {
+    info->library_name = "test";
+    info->library_version = "1";
+    info->valid_extensions = "pic";
+    info->need_fullpath = false;
+    info->block_extract = false;
+}
+
+void retro_get_system_av_info(struct retro_system_av_info *info) {
+    info->geometry.base_width = picture_width;
+    info->geometry.base_height = picture_height;
+    info->geometry.max_width = picture_width;
+    info->geometry.max_height = picture_height;
+    info->geometry.aspect_ratio = 0.0f;
+    info->timing.fps = 60.0;
+    info->timing.sample_rate = 48000.0;
+}
+
+void retro_set_controller_port_device(unsigned port, unsigned device) { (void)port; (void)device; }
+
+void retro_reset(void) {
+    memset(ram, 0, sizeof ram);
+}
+
+void retro_run(void) {
+    input_poll();
+
+    uint32_t frames;
+    memcpy(&frames, ram + RAM_FRAMES, sizeof frames);
+    frames++;
+    memcpy(ram + RAM_FRAMES, &frames, sizeof frames);
+
+    for (unsigned port = 0; port < PLAYERS; port++) {
+        put_u16(RAM_JOYPADS + port * 2, input_state(port, RETRO_DEVICE_JOYPAD, 0, RETRO_DEVICE_ID_JOYPAD_MASK));
+    }
+    ram[RAM_BUTTON_A] = input_state(0, RETRO_DEVICE_JOYPAD, 0, RETRO_DEVICE_ID_JOYPAD_A);
+    for (unsigned axis = 0; axis < 4; axis++) {
+        put_u16(RAM_ANALOG + axis * 2, input_state(0, RETRO_DEVICE_ANALOG, axis / 2, axis % 2));
+    }
+
+    video_refresh(picture, picture_width, picture_height, picture_pitch);
+}
+
+size_t retro_serialize_size(void) { return sizeof ram; }
+
+bool retro_serialize(void *data, size_t size) {
+    if (size < sizeof ram) {
+        return false;
+    }
+    memcpy(data, ram, sizeof ram);
+    return true;
+}
+
+bool retro_unserialize(const void *data, size_t size) {
+    if (size != sizeof ram) {
+        return false;
+    }
+    memcpy(ram, data, sizeof ram);
+    return true;
+}
+
+void retro_cheat_reset(void) {}
+void retro_cheat_set(unsigned index, bool enabled, const char *code) { (void)index; (void)enabled; (void)code; }
+
+bool retro_load_game(const struct retro_game_info *game) {
+    if (game == NULL || game->data == NULL || game->size < HEADER_SIZE) {
+        return false;
+    }
+
+    const uint8_t *content = game->data;
+    unsigned pixel_format = content[0];
+    size_t picture_size = game->size - HEADER_SIZE;
+    if (picture_size > MAX_PICTURE_SIZE || picture_size < (size_t)content[2] * content[3]) {
+        return false;
+    }
+    if (!environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &pixel_format)) {
+        return false;
+    }
+
+    picture_width = content[1];
+    picture_height = content[2];
+    picture_pitch = content[3];
+    memcpy(picture, content + HEADER_SIZE, picture_size);
+    memset(ram, 0, sizeof ram);
+    return true;
+}
+
+bool retro_load_game_special(unsigned type, const struct retro_game_info *info, size_t num) {
+    (void)type; (void)info; (void)num;
+    return false;
+}
+
+void retro_unload_game(void) {}
+unsigned retro_get_region(void) { return 0; }
+
+void *retro_get_memory_data(unsigned id) {
+    return id == RETRO_MEMORY_SYSTEM_RAM ? ram : NULL;
+}
+
+size_t retro_get_memory_size(unsigned id) {
+    return id == RETRO_MEMORY_SYSTEM_RAM ? sizeof ram : 0;
+}
@@ -0,0 +1,43 @@
+mod common;
+
+use libemu::{Emulator, EmuInputEvent, InputKind, INPUT_AXIS_MAX};
+
+use common::*;
+
+// retropad ids, see the button numbers of LibretroEmulator
+const BUTTON_B: u8 = 0;
+const BUTTON_START: u8 = 3;
+const BUTTON_A: u8 = 8;
+
+fn analog(emu: &impl Emulator) -> Vec<i16> {
+    (0..4).map(|axis| read_ram(emu, RAM_ANALOG + axis * 2, 2) as u16 as i16).collect()
+}
+
+#[test]
+fn retropads_are_read_as_the_core_asks() {
+    let dir = test_dir("input");
+    write_picture(&dir, "game", FORMAT_XRGB8888, 1, 1, 4, &[0, 0, 0, 0]);
+    let emu = create_emulator(&dir, 1, 1);
+    let handle = emu.run("game");
+
+    emu.put_input_event(EmuInputEvent::button(0, BUTTON_A, InputKind::INPUT_BUTTON_DOWN));
+    emu.put_input_event(EmuInputEvent::button(0, BUTTON_START, InputKind::INPUT_BUTTON_DOWN));
+    emu.put_input_event(EmuInputEvent::button(1, BUTTON_B, InputKind::INPUT_BUTTON_DOWN));
+    // axes 0 to 3 are the x and y of the left stick (index 0), then of the right one (index 1)
+    emu.put_input_event(EmuInputEvent::axis(0, 1, InputKind::INPUT_AXIS_ABSOLUTE, -INPUT_AXIS_MAX / 2));
+    emu.put_input_event(EmuInputEvent::axis(0, 2, InputKind::INPUT_AXIS_ABSOLUTE, INPUT_AXIS_MAX));
+    wait_until("the inputs", || read_ram(&emu, RAM_JOYPADS + 2, 2) != 0 && analog(&emu)[2] != 0);
+
+    assert_eq!(read_ram(&emu, RAM_JOYPADS, 2), (1 << BUTTON_A) | (1 << BUTTON_START));
+    assert_eq!(read_ram(&emu, RAM_JOYPADS + 2, 2), 1 << BUTTON_B);
+    assert_eq!(read_ram(&emu, RAM_JOYPADS + 4, 2), 0);
+    assert_eq!(read_ram(&emu, RAM_BUTTON_A, 1), 1);
+    assert_eq!(analog(&emu), vec![0, -16383, i16::MAX, 0]);
+
+    emu.put_input_event(EmuInputEvent::button(0, BUTTON_A, InputKind::INPUT_BUTTON_UP));
+    wait_until("button A to be released", || read_ram(&emu, RAM_BUTTON_A, 1) == 0);
+    assert_eq!(read_ram(&emu, RAM_JOYPADS, 2), 1 << BUTTON_START);
+
+    emu.stop();
+    handle.wait();
+}
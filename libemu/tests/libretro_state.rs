@@ -0,0 +1,48 @@
+mod common;
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use libemu::Emulator;
+
+use common::*;
+
+fn frames(emu: &impl Emulator) -> u64 {
+    read_ram(emu, RAM_FRAMES, 4)
+}
+
+#[test]
+fn states_are_saved_and_loaded_through_the_core() {
+    let dir = test_dir("state");
+    write_picture(&dir, "game", FORMAT_XRGB8888, 1, 1, 4, &[0, 0, 0, 0]);
+    let emu = create_emulator(&dir, 1, 1);
+    let handle = emu.run("game");
+    wait_until("a few frames", || frames(&emu) > 3);
+
+    emu.pause();
+    // a frame may still be running as it pauses
+    thread::sleep(Duration::from_millis(100));
+    let saved = frames(&emu);
+    emu.save_state("slot1").unwrap();
+    assert!(dir.join("sta").join("game").join("slot1.sta").is_file());
+
+    emu.step_frame().unwrap();
+    wait_until("a stepped frame", || frames(&emu) == saved + 1);
+    emu.load_state("slot1").unwrap();
+    assert_eq!(frames(&emu), saved);
+
+    let data = emu.save_state_data().unwrap();
+    emu.step_frame().unwrap();
+    wait_until("a stepped frame", || frames(&emu) == saved + 1);
+    emu.load_state_data(&data).unwrap();
+    assert_eq!(frames(&emu), saved);
+
+    // the core refuses a state of another size, which leaves the game as it was
+    fs::write(dir.join("sta").join("game").join("short.sta"), &data[..data.len() / 2]).unwrap();
+    assert!(emu.load_state("short").is_err());
+    assert_eq!(frames(&emu), saved);
+
+    emu.stop();
+    handle.wait();
+}
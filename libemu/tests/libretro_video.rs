@@ -0,0 +1,91 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libemu::{Emulator, EmuEvent, EmuRunHandle};
+
+use common::*;
+
+// the rows of the pictures are padded, which must not show up in the frames
+const PADDING: u8 = 0xaa;
+
+fn picture_16bits(pixels: [u16; 4], pitch: usize) -> Vec<u8> {
+    let mut buf = vec![];
+    for row in pixels.chunks(2) {
+        for pixel in row {
+            buf.extend_from_slice(&pixel.to_ne_bytes());
+        }
+        buf.resize(buf.len() + pitch - 4, PADDING);
+    }
+    buf
+}
+
+fn picture_32bits(pixels: [u32; 4], pitch: usize) -> Vec<u8> {
+    let mut buf = vec![];
+    for row in pixels.chunks(2) {
+        for pixel in row {
+            buf.extend_from_slice(&pixel.to_ne_bytes());
+        }
+        buf.resize(buf.len() + pitch - 8, PADDING);
+    }
+    buf
+}
+
+fn bgra(pixels: [[u8; 4]; 4]) -> Vec<u8> {
+    pixels.iter().flatten().cloned().collect()
+}
+
+fn wait_for_loaded(handle: &EmuRunHandle, system_name: &str) {
+    loop {
+        match handle.events().recv_timeout(Duration::from_secs(5)) {
+            Ok(EmuEvent::SystemLoaded(name)) if name == system_name => return,
+            Ok(_) => continue,
+            Err(e) => panic!("{} was not loaded: {}", system_name, e),
+        }
+    }
+}
+
+fn next_frame(frame: &Arc<Mutex<Option<Vec<u8>>>>) -> Vec<u8> {
+    *frame.lock().unwrap() = None;
+    wait_until("a frame", || frame.lock().unwrap().is_some());
+    frame.lock().unwrap().take().unwrap()
+}
+
+#[test]
+fn pictures_of_every_pixel_format_are_converted_to_bgra() {
+    let dir = test_dir("video");
+    // red, green, blue and a grey of 16 in every 5 bits, or 32 in the 6 bits of green in RGB565
+    write_picture(&dir, "rgb1555", FORMAT_0RGB1555, 2, 2, 6,
+        &picture_16bits([0x7c00, 0x03e0, 0x001f, 0x4210], 6));
+    write_picture(&dir, "rgb565", FORMAT_RGB565, 2, 2, 8,
+        &picture_16bits([0xf800, 0x07e0, 0x001f, 0x8410], 8));
+    // the unused byte of XRGB8888 is not taken for alpha
+    write_picture(&dir, "xrgb8888", FORMAT_XRGB8888, 2, 2, 12,
+        &picture_32bits([0x77ff0000, 0x0000ff00, 0x000000ff, 0x00102030], 12));
+
+    let mut emu = create_emulator(&dir, 2, 2);
+    let frame = Arc::new(Mutex::new(None));
+    let frame_cb = frame.clone();
+    let _guard = emu.set_image_frame_cb(move |image| {
+        assert_eq!((image.width, image.height, image.stride), (2, 2, 8));
+        *frame_cb.lock().unwrap() = Some(image.buf.to_vec());
+    });
+
+    let handle = emu.run("rgb1555");
+    assert_eq!(next_frame(&frame),
+        bgra([[0, 0, 255, 255], [0, 255, 0, 255], [255, 0, 0, 255], [132, 132, 132, 255]]));
+
+    emu.load_system("rgb565").unwrap();
+    wait_for_loaded(&handle, "rgb565");
+    assert_eq!(next_frame(&frame),
+        bgra([[0, 0, 255, 255], [0, 255, 0, 255], [255, 0, 0, 255], [132, 130, 132, 255]]));
+
+    emu.load_system("xrgb8888").unwrap();
+    wait_for_loaded(&handle, "xrgb8888");
+    assert_eq!(next_frame(&frame),
+        bgra([[0, 0, 255, 255], [0, 255, 0, 255], [255, 0, 0, 255], [0x30, 0x20, 0x10, 255]]));
+
+    emu.stop();
+    handle.wait();
+}
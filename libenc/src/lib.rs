@@ -1,4 +1,4 @@
-use std::ptr;
+use std::slice;
 use std::sync::Arc;
 use std::ops::Sub;
 use std::time::Duration;
@@ -11,50 +11,138 @@ use libvpx::encoder::VP9_DESCR;
 use libopus::encoder::OPUS_DESCR;
 use x264;
 
+use libbuf::{BufferPool, PooledBuffer};
+
 mod utils;
 
 const FRAME_EXPIRE_DURATION: Duration = Duration::from_millis(30);
+// encoded frames in flight to the output sockets
+const ENCODED_POOL_SIZE: usize = 16;
+
+// Opus takes 48 kHz sound in frames of a few fixed durations
+const OPUS_SAMPLE_RATE: usize = 48000;
+pub const OPUS_FRAME_DURATIONS_MS: [usize; 2] = [10, 20];
+// sound arriving further than this from where the last one ended starts a new run of frames
+const OPUS_GAP_TOLERANCE: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PixelFormat {
+    BGRA32,
+    RGBA32,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::BGRA32 | PixelFormat::RGBA32 => 4,
+        }
+    }
+}
+
+// stride is the number of bytes per row, which may be larger than width * bytes per pixel
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ImageLayout {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+impl ImageLayout {
+    pub fn validate(&self, buf_size: usize) -> Result<(), String> {
+        let row_size = self.width * self.format.bytes_per_pixel();
+        if self.width == 0 || self.height == 0 {
+            return Err(format!("empty image {}x{}", self.width, self.height));
+        }
+        if self.width % 2 != 0 || self.height % 2 != 0 {
+            return Err(format!("image size {}x{} is not even", self.width, self.height));
+        }
+        if self.stride < row_size {
+            return Err(format!("stride {} is shorter than a row of {} bytes", self.stride, row_size));
+        }
+        if buf_size < self.stride * (self.height - 1) + row_size {
+            return Err(format!("buffer of {} bytes is too small for {:?}", buf_size, self));
+        }
+        Ok(())
+    }
+}
 
 pub struct VideoFrame {
-    pub buf: Vec<u8>,
+    pub buf: PooledBuffer<u8>,
+    pub layout: ImageLayout,
+    pub frame_number: u64,
     pub timestamp: Duration,
+    pub emu_timestamp: Duration,
 }
 
 impl VideoFrame {
-    pub fn from(buf: &[u8], timestamp: Duration) -> VideoFrame {
+    pub fn from(
+        buf: PooledBuffer<u8>, layout: ImageLayout, frame_number: u64, timestamp: Duration, emu_timestamp: Duration) -> VideoFrame {
+
         VideoFrame {
-            buf: Vec::from(buf),
+            buf: buf,
+            layout: layout,
+            frame_number: frame_number,
             timestamp: timestamp,
+            emu_timestamp: emu_timestamp,
         }
     }
 }
 
+// buf holds samples interleaved by channel, samples being the number per channel
 pub struct AudioFrame {
-    buf: Vec<i16>,
+    buf: PooledBuffer<i16>,
     timestamp: Duration,
+    emu_timestamp: Duration,
     samples: usize,
     sample_rate: usize,
+    channels: usize,
 }
 
 impl AudioFrame {
-    pub fn from(buf: &[i16], timestamp: Duration, samples: usize, sample_rate: usize) -> AudioFrame {
+    pub fn from(
+        buf: PooledBuffer<i16>, timestamp: Duration, emu_timestamp: Duration,
+        samples: usize, sample_rate: usize, channels: usize) -> AudioFrame {
+
         AudioFrame {
-            buf: Vec::from(buf),
+            buf: buf,
             timestamp: timestamp,
+            emu_timestamp: emu_timestamp,
             samples: samples,
             sample_rate: sample_rate,
+            channels: channels,
+        }
+    }
+}
+
+// Y, U and V planes of a yuv420 picture, kept by the encoders and reused across frames
+struct YuvPlanes {
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+}
+
+impl YuvPlanes {
+    fn new(w: usize, h: usize) -> YuvPlanes {
+        YuvPlanes {
+            y: vec![0u8; w * h],
+            u: vec![0u8; w * h / 4],
+            v: vec![0u8; w * h / 4],
         }
     }
 }
 
 pub struct EncodedFrame {
-    pub buf: Vec<u8>,
+    pub buf: PooledBuffer<u8>,
     pub timestamp: Duration,
 }
 
 pub trait Encoder {
     fn encode_video(&mut self, frame: &VideoFrame) -> Result<EncodedFrame, String>;
-    fn encode_audio(&mut self, frame: &AudioFrame) -> Result<EncodedFrame, String>;
+    // sound is cut into frames the codec takes, so a raw frame may encode to none or several
+    fn encode_audio(&mut self, frame: &AudioFrame) -> Result<Vec<EncodedFrame>, String>;
+    // the next frame is encoded as a keyframe, audio frames don't depend on each other
+    fn force_keyframe(&mut self) {}
 }
 
 pub struct Vp9Encoder {
@@ -64,8 +152,8 @@ pub struct Vp9Encoder {
     keyframe_interval: usize,
 
     enc_ctx: av_codec::encoder::Context,
+    yuv: YuvPlanes,
 
-    frame_index: i64,
     encoded_frame_count: i64,
 }
 
@@ -77,7 +165,7 @@ impl Vp9Encoder {
             fps: fps,
             keyframe_interval: keyframe_interval,
             enc_ctx: Vp9Encoder::create_ctx(w, h),
-            frame_index: 0,
+            yuv: YuvPlanes::new(w, h),
             encoded_frame_count: 0
         }
     }
@@ -91,6 +179,17 @@ impl Vp9Encoder {
         }
     }
 
+    // follow the size of incoming frames, the new context starts with a keyframe
+    fn adapt_to(&mut self, layout: &ImageLayout) {
+        if layout.width != self.w || layout.height != self.h {
+            self.w = layout.width;
+            self.h = layout.height;
+            self.enc_ctx = Vp9Encoder::create_ctx(self.w, self.h);
+            self.yuv = YuvPlanes::new(self.w, self.h);
+            self.encoded_frame_count = 0;
+        }
+    }
+
     fn create_ctx(w: usize, h: usize) -> av_codec::encoder::Context {
         let codec_info = av_data::params::VideoInfo {
             width: w,
@@ -118,33 +217,33 @@ impl Vp9Encoder {
 }
 
 impl Encoder for Vp9Encoder {
-    fn encode_audio(&mut self, _frame: &AudioFrame) -> Result<EncodedFrame, String> {
+    fn encode_audio(&mut self, _frame: &AudioFrame) -> Result<Vec<EncodedFrame>, String> {
         unimplemented!();
     }
 
+    fn force_keyframe(&mut self) {
+        self.encoded_frame_count = 0;
+    }
+
     fn encode_video(&mut self, frame: &VideoFrame) -> Result<EncodedFrame, String> {
         // skip encoding for expired frame
         let now = utils::time::now_utc();
         let expired = now.sub(FRAME_EXPIRE_DURATION);
         if frame.timestamp < expired {
-            self.frame_index += 1;
             return Err(format!("raw frame is decayed, dropping.. {:?} < {:?}", frame.timestamp, expired));
         }
 
-        let yuv_size = self.w * self.h;
-        let chroma_size = yuv_size / 4;
+        frame.layout.validate(frame.buf.len())?;
+        self.adapt_to(&frame.layout);
 
         // Regarding timebase and pts,
         // https://stackoverflow.com/questions/43333542/what-is-video-timescale-timebase-or-timestamp-in-ffmpeg/43337235
-        let mut out_y = vec![0u8; yuv_size];
-        let mut out_u = vec![0u8; chroma_size];
-        let mut out_v = vec![0u8; chroma_size];
-        utils::converter::bgra_to_yuv420(
-            self.w, self.h, &frame.buf, out_y.as_mut(), out_u.as_mut(), out_v.as_mut());
+        let yuv = &mut self.yuv;
+        utils::converter::to_yuv420(&frame.layout, &frame.buf, &mut yuv.y, &mut yuv.u, &mut yuv.v)?;
         // println!("yuv frame size: y: {}, u: {}, v: {}", y.len(), u.len(), v.len());
 
-        let yuv_bufs = [out_y, out_u, out_v];
-        let source = yuv_bufs.iter().map(|v| v.as_ref());
+        let yuv_bufs = [&self.yuv.y, &self.yuv.u, &self.yuv.v];
+        let source = yuv_bufs.iter().map(|v| v.as_slice());
 
         // https://stackoverflow.com/questions/13286022/can-anyone-help-in-understanding-avframe-linesize
         let yuv_strides = [self.w, self.w / 2, self.w / 2];
@@ -154,7 +253,7 @@ impl Encoder for Vp9Encoder {
             let time_info = {
                 let mut ti: av_data::timeinfo::TimeInfo = av_data::timeinfo::TimeInfo::default();
                 ti.timebase = Some(av_data::rational::Rational64::new(1, self.fps as i64));
-                ti.pts = Some(self.frame_index);
+                ti.pts = Some(utils::time::to_pts(frame.emu_timestamp, self.fps));
                 ti
             };
 
@@ -177,38 +276,52 @@ impl Encoder for Vp9Encoder {
             self.enc_ctx.receive_packet().unwrap()
         };
 
-        self.frame_index += 1;
         self.encoded_frame_count += 1;
 
         Ok(EncodedFrame {
-            buf: encoded_packet.data,
+            buf: PooledBuffer::from_vec(encoded_packet.data),
             timestamp: frame.timestamp,
         })
     }
 }
 
+// Sound is mixed to the channels of the stream, resampled to 48 kHz and gathered until there's
+// a whole opus frame of frame_samples per channel, so the size of the raw frames doesn't matter.
+// pts count samples at 48 kHz on the emulated clock.
 pub struct OpusEncoder {
-    fps: usize,
+    channels: usize,
 
     enc_ctx: av_codec::encoder::Context,
+    resampler: Option<utils::resample::Resampler>,
 
-    frame_index: i64,
+    remixed: Vec<i16>,
+    chunker: utils::chunk::SoundChunker,
 }
 
 impl OpusEncoder {
-    pub fn create(fps: usize) -> impl Encoder {
-        OpusEncoder {
-            fps: fps,
-            enc_ctx: OpusEncoder::create_ctx(),
-            frame_index: 0,
+    // channels is 1 for mono or 2 for stereo
+    pub fn create(frame_duration_ms: usize, channels: usize) -> Result<impl Encoder, String> {
+        if !OPUS_FRAME_DURATIONS_MS.contains(&frame_duration_ms) {
+            return Err(format!("opus frame duration {} ms is not one of {:?}", frame_duration_ms, OPUS_FRAME_DURATIONS_MS));
         }
+        if channels != 1 && channels != 2 {
+            return Err(format!("opus channels {} is neither mono nor stereo", channels));
+        }
+
+        let frame_samples = OPUS_SAMPLE_RATE * frame_duration_ms / 1000;
+        Ok(OpusEncoder {
+            channels: channels,
+            enc_ctx: OpusEncoder::create_ctx(channels),
+            resampler: None,
+            remixed: vec![],
+            chunker: utils::chunk::SoundChunker::new(frame_samples, channels),
+        })
     }
 
-    fn create_ctx() -> av_codec::encoder::Context {
-        // let audio_channel_map = av_data::audiosample::ChannelMap::default_map(2);
+    fn create_ctx(channels: usize) -> av_codec::encoder::Context {
         let codec_info = av_data::params::AudioInfo {
-            rate: 0,
-            map: Some(OpusEncoder::channel_map_mono()),
+            rate: OPUS_SAMPLE_RATE,
+            map: Some(av_data::audiosample::ChannelMap::default_map(channels)),
             format: None,
         };
 
@@ -241,8 +354,50 @@ impl OpusEncoder {
         }
     }
 
-    fn channel_map_mono() -> av_data::audiosample::ChannelMap {
-        av_data::audiosample::ChannelMap::default_map(1)
+    // takes the context alone, so that samples may be borrowed from the encoder itself
+    fn encode_frame(
+        enc_ctx: &mut av_codec::encoder::Context, channels: usize, samples: &[i16], pts: i64) -> Result<Vec<u8>, String> {
+
+        let time_info = {
+            let mut ti: av_data::timeinfo::TimeInfo = av_data::timeinfo::TimeInfo::default();
+            ti.timebase = Some(av_data::rational::Rational64::new(1, OPUS_SAMPLE_RATE as i64));
+            ti.pts = Some(pts);
+            ti
+        };
+
+        let frame_info = av_data::frame::AudioInfo {
+            samples: samples.len() / channels,
+            rate: OPUS_SAMPLE_RATE,
+            map: av_data::audiosample::ChannelMap::default_map(channels),
+            format: Arc::new(OpusEncoder::soniton()),
+        };
+
+        let frame_kind = av_data::frame::MediaKind::Audio(frame_info);
+        let av_frame = {
+            let mut f = av_data::frame::new_default_frame(frame_kind, Some(time_info));
+            let mut buf = f.buf.as_mut_slice_inner(0).unwrap();
+            utils::copy::copy_sound_samples(samples, &mut buf);
+            Arc::new(f)
+        };
+
+        enc_ctx.send_frame(&av_frame)
+            .and_then(|_| enc_ctx.flush())
+            .and_then(|_| enc_ctx.receive_packet())
+            .map(|packet| packet.data)
+            .map_err(|e| format!("failed to encode sound: {:?}", e))
+    }
+
+    // Encodes whatever sound is pending as a frame padded with silence, ex, ahead of a gap
+    fn flush_pending(&mut self, timestamp: Duration, encoded: &mut Vec<EncodedFrame>) -> Result<(), String> {
+        let (enc_ctx, channels) = (&mut self.enc_ctx, self.channels);
+        self.chunker.flush(|samples, pts| {
+            let buf = OpusEncoder::encode_frame(enc_ctx, channels, samples, pts)?;
+            encoded.push(EncodedFrame {
+                buf: PooledBuffer::from_vec(buf),
+                timestamp: timestamp,
+            });
+            Ok(())
+        })
     }
 }
 
@@ -251,52 +406,43 @@ impl Encoder for OpusEncoder {
         unimplemented!();
     }
 
-    fn encode_audio(&mut self, frame: &AudioFrame) -> Result<EncodedFrame, String> {
+    fn encode_audio(&mut self, frame: &AudioFrame) -> Result<Vec<EncodedFrame>, String> {
         let now = utils::time::now_utc();
         let expired = now.sub(FRAME_EXPIRE_DURATION);
         if frame.timestamp < expired {
-            self.frame_index += 1;
-
             return Err(format!("raw frame is decayed, dropping.. {:?} < {:?}", frame.timestamp, expired));
         }
-
-        let av_frame = {
-            let time_info = {
-                let mut ti: av_data::timeinfo::TimeInfo = av_data::timeinfo::TimeInfo::default();
-                ti.timebase = Some(av_data::rational::Rational64::new(1, self.fps as i64));
-                ti.pts = Some(self.frame_index);
-                ti
-            };
-
-            let frame_info = av_data::frame::AudioInfo {
-                samples: frame.samples,
-                rate: frame.sample_rate,
-                map: OpusEncoder::channel_map_mono(),
-                format: Arc::new(OpusEncoder::soniton()),
-            };
-
-            let frame_kind = av_data::frame::MediaKind::Audio(frame_info.clone());
-            let arc_frame = {
-                let mut f = av_data::frame::new_default_frame(frame_kind, Some(time_info));
-                let mut buf = f.buf.as_mut_slice_inner(0).unwrap();
-                utils::copy::copy_interleaved_sound_samples_mono(frame.buf.as_ref(), &mut buf);
-                Arc::new(f)
-            };
-            arc_frame
+        let src = match frame.buf.get(..frame.samples * frame.channels) {
+            Some(src) if frame.channels > 0 => src,
+            _ => return Err(format!("sound frame of {} bytes doesn't hold {} samples of {} channels",
+                frame.buf.len() * 2, frame.samples, frame.channels)),
         };
 
-        let encoded_packet = {
-            self.enc_ctx.send_frame(&av_frame).unwrap();
-            self.enc_ctx.flush().unwrap();
-            self.enc_ctx.receive_packet().unwrap()
-        };
+        let mut encoded = vec![];
+        let frame_pts = utils::time::to_pts(frame.emu_timestamp, OPUS_SAMPLE_RATE);
+        let tolerance = utils::time::to_pts(OPUS_GAP_TOLERANCE, OPUS_SAMPLE_RATE);
+        let rate_changed = self.resampler.as_ref().map_or(true, |r| r.in_rate() != frame.sample_rate);
+        if rate_changed || (frame_pts - self.chunker.next_pts()).abs() > tolerance {
+            self.flush_pending(frame.timestamp, &mut encoded)?;
+            self.resampler = Some(utils::resample::Resampler::new(frame.sample_rate, OPUS_SAMPLE_RATE, self.channels));
+            self.chunker.start_at(frame_pts);
+        }
 
-        self.frame_index += 1;
+        utils::copy::remix(src, frame.channels, self.channels, &mut self.remixed);
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.process(&self.remixed, self.chunker.pending_mut());
+        }
 
-        Ok(EncodedFrame {
-            buf: encoded_packet.data,
-            timestamp: frame.timestamp,
-        })
+        let (enc_ctx, channels, timestamp) = (&mut self.enc_ctx, self.channels, frame.timestamp);
+        self.chunker.drain_frames(|samples, pts| {
+            let buf = OpusEncoder::encode_frame(enc_ctx, channels, samples, pts)?;
+            encoded.push(EncodedFrame {
+                buf: PooledBuffer::from_vec(buf),
+                timestamp: timestamp,
+            });
+            Ok(())
+        })?;
+        Ok(encoded)
     }
 }
 
@@ -309,8 +455,10 @@ pub struct H264Encoder {
 
     enc_params: x264::Param,
     enc_ctx: x264::Encoder,
+    // frames are converted straight into the planes of this picture, the encoder copying them in
+    pic: Option<x264::Picture>,
+    nal_pool: BufferPool<u8>,
 
-    frame_index: i64,
     encoded_frame_count: i64,
 }
 
@@ -320,6 +468,7 @@ impl H264Encoder {
     pub fn create(w: usize, h: usize, fps: usize, keyframe_interval: usize) -> impl Encoder {
         let mut enc_params = H264Encoder::create_enc_params(w, h, keyframe_interval);
         let enc_ctx = x264::Encoder::open(&mut enc_params).unwrap();
+        let pic = x264::Picture::from_param(&enc_params).unwrap();
 
         H264Encoder {
             w: w,
@@ -328,11 +477,24 @@ impl H264Encoder {
             keyframe_interval: keyframe_interval,
             enc_params: enc_params,
             enc_ctx: enc_ctx,
-            frame_index: 0,
+            pic: Some(pic),
+            nal_pool: BufferPool::create(ENCODED_POOL_SIZE),
             encoded_frame_count: 0
         }
     }
 
+    // follow the size of incoming frames, the reopened encoder starts with a keyframe
+    fn adapt_to(&mut self, layout: &ImageLayout) {
+        if layout.width != self.w || layout.height != self.h {
+            self.w = layout.width;
+            self.h = layout.height;
+            self.enc_params = H264Encoder::create_enc_params(self.w, self.h, self.keyframe_interval);
+            self.enc_ctx = x264::Encoder::open(&mut self.enc_params).unwrap();
+            self.pic = Some(x264::Picture::from_param(&self.enc_params).unwrap());
+            self.encoded_frame_count = 0;
+        }
+    }
+
     fn create_enc_params(w: usize, h: usize, kf_interval: usize) -> x264::Param {
         // https://obsproject.com/forum/resources/low-latency-high-performance-x264-options-for-for-most-streaming-services-youtube-facebook.726/
         // x264::Param::default_preset("zerolatency", "ultrafast").unwrap()
@@ -388,53 +550,62 @@ impl H264Encoder {
 }
 
 impl Encoder for H264Encoder {
-    fn encode_audio(&mut self, _frame: &AudioFrame) -> Result<EncodedFrame, String> {
+    fn encode_audio(&mut self, _frame: &AudioFrame) -> Result<Vec<EncodedFrame>, String> {
         unimplemented!();
     }
 
+    // a reopened encoder starts with an IDR frame and takes timestamps starting over
+    fn force_keyframe(&mut self) {
+        self.enc_ctx = x264::Encoder::open(&mut self.enc_params).unwrap();
+        self.encoded_frame_count = 0;
+    }
+
     fn encode_video(&mut self, frame: &VideoFrame) -> Result<EncodedFrame, String> {
         // skip encoding for expired frame
         let now = utils::time::now_utc();
         let expired = now.sub(FRAME_EXPIRE_DURATION);
         if frame.timestamp < expired {
-            self.frame_index += 1;
             return Err(format!("raw frame is decayed, dropping.. {:?} < {:?}", frame.timestamp, expired));
         }
 
-        let yuv_size = self.w * self.h;
-        let chroma_size = yuv_size / 4;
-
-        let mut y = vec![0u8; yuv_size];
-        let mut u = vec![0u8; chroma_size];
-        let mut v = vec![0u8; chroma_size];
-        utils::converter::bgra_to_yuv420(self.w, self.h, &frame.buf, &mut y, &mut u, &mut v);
-
-        let mut pic = x264::Picture::from_param(&self.enc_params).unwrap()
-            .set_timestamp(self.frame_index);
-        self.frame_index += 1;
+        frame.layout.validate(frame.buf.len())?;
+        self.adapt_to(&frame.layout);
 
-        unsafe {
-            ptr::copy(y.as_ptr(), pic.as_mut_slice(0).unwrap().as_mut_ptr(), yuv_size);
-            ptr::copy(u.as_ptr(), pic.as_mut_slice(1).unwrap().as_mut_ptr(), chroma_size);
-            ptr::copy(v.as_ptr(), pic.as_mut_slice(2).unwrap().as_mut_ptr(), chroma_size);
+        let mut pic = self.pic.take()
+            .unwrap_or_else(|| x264::Picture::from_param(&self.enc_params).unwrap())
+            .set_timestamp(utils::time::to_pts(frame.emu_timestamp, self.fps));
+        let converted = {
+            let (y, u, v) = unsafe { (picture_plane(&mut pic, 0), picture_plane(&mut pic, 1), picture_plane(&mut pic, 2)) };
+            utils::converter::to_yuv420(&frame.layout, &frame.buf, y, u, v)
         };
+        let result = match converted {
+            Ok(_) => match self.enc_ctx.encode(&pic) {
+                Ok(Some((nal, _, _))) => {
+                    let encoded = self.nal_pool.acquire_from(nal.as_bytes());
 
-        match self.enc_ctx.encode(&pic) {
-            Ok(Some((nal, _, _))) => {
-                let encoded = Vec::from(nal.as_bytes());
+                    self.encoded_frame_count += 1;
 
-                self.encoded_frame_count += 1;
-
-                Ok(EncodedFrame { buf: encoded, timestamp: frame.timestamp, })
-            },
+                    Ok(EncodedFrame { buf: encoded, timestamp: frame.timestamp, })
+                },
 
-            Ok(None) => {
-                Err(format!("nothing encoded.."))
-            }
+                Ok(None) => {
+                    Err(format!("nothing encoded.."))
+                }
 
-            Err(e) => {
-                Err(format!("failed to encode frame.. {}", e))
-            }
-        }
+                Err(e) => {
+                    Err(format!("failed to encode frame.. {}", e))
+                }
+            },
+            Err(msg) => Err(msg),
+        };
+        self.pic = Some(pic);
+        result
     }
 }
+
+// The planes of a picture don't overlap, which borrowing them one at a time can't tell.
+// The slices must not outlive pic.
+unsafe fn picture_plane<'a>(pic: &mut x264::Picture, plane: usize) -> &'a mut [u8] {
+    let buf = pic.as_mut_slice(plane).unwrap();
+    slice::from_raw_parts_mut(buf.as_mut_ptr(), buf.len())
+}
@@ -1,30 +1,36 @@
 pub mod converter {
+    use crate::{ImageLayout, PixelFormat};
+
     // https://en.wikipedia.org/wiki/Chroma_subsampling
-    pub fn bgra_to_yuv420(width: usize, height: usize, bgra: &[u8], y: &mut [u8], u: &mut [u8], v: &mut [u8]) {
-        const BYTES_PER_PIXEL: usize = 4;
-
-        let num_pixels = width * height;
-        let mut uv_idx = 0;
-        for i in 0..num_pixels {
-            let b = i32::from(bgra[(i*BYTES_PER_PIXEL) + 0]);
-            let g = i32::from(bgra[(i*BYTES_PER_PIXEL) + 1]);
-            let r = i32::from(bgra[(i*BYTES_PER_PIXEL) + 2]);
-
-            // w = 10
-            //  0  1  2  3  4  5  6  7  8  9
-            // 10 11 12 13 14 15 16 17 18 19
-            // (0, 1, 10, 11) --> Same U, V
-            // (2, 3, 12, 13) --> Same U, V
-            // (4, 5, 14, 15) --> Same U, V
-            // ...
-
-            y[i] = clamp((77*r + 150*g + 29*b + 128) >> 8);
-            if i % 2 == 0 && (i/width) % 2 == 0 {
-                u[uv_idx] = clamp(((-43*r - 84*g + 127*b) >> 8) + 128);
-                v[uv_idx] = clamp(((127*r - 106*g - 21*b) >> 8) + 128);
-                uv_idx += 1;
+    pub fn to_yuv420(layout: &ImageLayout, src: &[u8], y: &mut [u8], u: &mut [u8], v: &mut [u8]) -> Result<(), String> {
+        layout.validate(src.len())?;
+
+        let (r_idx, b_idx) = match layout.format {
+            PixelFormat::BGRA32 => (2, 0),
+            PixelFormat::RGBA32 => (0, 2),
+        };
+        let bpp = layout.format.bytes_per_pixel();
+        let width = layout.width;
+        let chroma_width = (width + 1) / 2;
+
+        for row in 0..layout.height {
+            let line = &src[row * layout.stride..];
+            for col in 0..width {
+                let b = i32::from(line[col * bpp + b_idx]);
+                let g = i32::from(line[col * bpp + 1]);
+                let r = i32::from(line[col * bpp + r_idx]);
+
+                // (0, 0), (1, 0), (0, 1), (1, 1) --> Same U, V
+                y[row * width + col] = clamp((77*r + 150*g + 29*b + 128) >> 8);
+                if row % 2 == 0 && col % 2 == 0 {
+                    let uv_idx = (row / 2) * chroma_width + col / 2;
+                    u[uv_idx] = clamp(((-43*r - 84*g + 127*b) >> 8) + 128);
+                    v[uv_idx] = clamp(((127*r - 106*g - 21*b) >> 8) + 128);
+                }
             }
         }
+
+        Ok(())
     }
 
     fn clamp(val: i32) -> u8 {
@@ -39,29 +45,250 @@ pub mod converter {
 pub mod copy {
     use std::mem;
 
-    // fn copy_interleaved_sound_samples(src: &[i16], dst_frame: &mut av_data::frame::Frame) {
-    //     let samples = src.len() / 2;
-
-    //     let l = {
-    //         let buf = dst_frame.buf.as_mut_slice_inner(0).unwrap();
-    //         unsafe { mem::transmute::<&mut [u8], &mut [i16]>(buf) }
-    //     };
-    //     let r = {
-    //         let buf = dst_frame.buf.as_mut_slice_inner(1).unwrap();
-    //         unsafe { mem::transmute::<&mut [u8], &mut [i16]>(buf) }
-    //     };
-    //     for i in 0..samples {
-    //         l[i] = src[i*2];
-    //         r[i] = src[i*2+1];
-    //     }
-    // }
-
-    pub fn copy_interleaved_sound_samples_mono(src: &[i16], dst_buf: &mut [u8]) {
-        let samples = src.len() / 2;
+    // Converts interleaved samples to another number of channels. Mono is the average of
+    // every channel, and stereo takes the first two channels or doubles a single one.
+    pub fn remix(src: &[i16], src_channels: usize, dst_channels: usize, dst: &mut Vec<i16>) {
+        dst.clear();
+        if src_channels == dst_channels {
+            dst.extend_from_slice(src);
+            return;
+        }
+
+        for frame in src.chunks_exact(src_channels) {
+            match (src_channels, dst_channels) {
+                (_, 1) => {
+                    let sum: i32 = frame.iter().map(|&v| v as i32).sum();
+                    dst.push((sum / src_channels as i32) as i16);
+                },
+                (1, _) => dst.extend((0..dst_channels).map(|_| frame[0])),
+                _ => dst.extend_from_slice(&frame[..dst_channels]),
+            }
+        }
+    }
 
+    pub fn copy_sound_samples(src: &[i16], dst_buf: &mut [u8]) {
         let b = unsafe { mem::transmute::<&mut [u8], &mut [i16]>(dst_buf) };
-        for i in 0..samples {
-            b[i] = src[i*2];
+        b[..src.len()].copy_from_slice(src);
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn mono_is_doubled_into_stereo() {
+            let mut dst = vec![9; 3];
+            remix(&[1, -2, 3], 1, 2, &mut dst);
+            assert_eq!(dst, vec![1, 1, -2, -2, 3, 3]);
+        }
+
+        #[test]
+        fn stereo_is_averaged_into_mono() {
+            let mut dst = vec![];
+            remix(&[100, 300, -5, 5, i16::MAX, i16::MAX, i16::MIN, i16::MIN], 2, 1, &mut dst);
+            assert_eq!(dst, vec![200, 0, i16::MAX, i16::MIN]);
+        }
+    }
+}
+
+pub mod resample {
+    // Converts interleaved sound to another sample rate by linear interpolation, carrying the
+    // position between calls so that consecutive frames resample as one continuous stream.
+    pub struct Resampler {
+        in_rate: usize,
+        out_rate: usize,
+        channels: usize,
+        // where the next output sample falls after the last input sample of the previous call, in
+        // 1/out_rate of an input sample so that it adds up exactly however the sound is split
+        pos: usize,
+        last: Vec<i16>,
+    }
+
+    impl Resampler {
+        pub fn new(in_rate: usize, out_rate: usize, channels: usize) -> Resampler {
+            Resampler {
+                in_rate: in_rate,
+                out_rate: out_rate,
+                channels: channels,
+                // the first output sample is the first input sample
+                pos: out_rate,
+                last: vec![],
+            }
+        }
+
+        pub fn in_rate(&self) -> usize {
+            self.in_rate
+        }
+
+        pub fn process(&mut self, src: &[i16], dst: &mut Vec<i16>) {
+            let channels = self.channels;
+            let frames = src.len() / channels;
+            if self.in_rate == self.out_rate || frames == 0 {
+                dst.extend_from_slice(&src[..frames * channels]);
+                return;
+            }
+
+            // the last sample of the previous call sits at 0, sample i of src at i + 1
+            let last = if self.last.is_empty() { &src[..channels] } else { &self.last[..] };
+            let at = |i: usize, c: usize| if i == 0 { last[c] } else { src[(i - 1) * channels + c] };
+            while self.pos < frames * self.out_rate {
+                let idx = self.pos / self.out_rate;
+                let frac = (self.pos % self.out_rate) as f64 / self.out_rate as f64;
+                for c in 0..channels {
+                    let (a, b) = (at(idx, c) as f64, at(idx + 1, c) as f64);
+                    dst.push((a + (b - a) * frac).round() as i16);
+                }
+                self.pos += self.in_rate;
+            }
+            self.pos -= frames * self.out_rate;
+            self.last.clear();
+            self.last.extend_from_slice(&src[(frames - 1) * channels..frames * channels]);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn a_second_of_44100_hz_comes_out_at_48000_hz() {
+            let mut resampler = Resampler::new(44100, 48000, 2);
+            let mut dst = vec![];
+            for _ in 0..100 {
+                resampler.process(&[100; 441 * 2], &mut dst);
+            }
+            // the last sample waits for the next call, which it is interpolated towards
+            assert_eq!(dst.len(), 47999 * 2);
+            assert!(dst.iter().all(|&v| v == 100));
+            resampler.process(&[100; 441 * 2], &mut dst);
+            assert_eq!(dst.len(), 48479 * 2);
+        }
+
+        #[test]
+        fn sound_carries_over_from_call_to_call() {
+            let src: Vec<i16> = (0..1000).map(|i| i * 10).collect();
+            let mut whole = vec![];
+            Resampler::new(44100, 48000, 1).process(&src, &mut whole);
+
+            let mut chunked = vec![];
+            let mut resampler = Resampler::new(44100, 48000, 1);
+            for chunk in src.chunks(77) {
+                resampler.process(chunk, &mut chunked);
+            }
+            assert_eq!(chunked, whole);
+            // a ramp stays a ramp, one input sample being 48000 / 44100 output samples apart
+            assert_eq!(whole.len(), 1088);
+            for (k, &v) in whole.iter().enumerate() {
+                assert_eq!(v, (k as f64 * 441.0 / 480.0 * 10.0).round() as i16);
+            }
+        }
+    }
+}
+
+pub mod chunk {
+    // Gathers interleaved sound into frames of frame_samples per channel, the pts of every frame
+    // counting samples on from the pts of the first sample put in.
+    pub struct SoundChunker {
+        frame_samples: usize,
+        channels: usize,
+        // sound short of a whole frame, and the pts of its first sample
+        pending: Vec<i16>,
+        pending_pts: i64,
+    }
+
+    impl SoundChunker {
+        pub fn new(frame_samples: usize, channels: usize) -> SoundChunker {
+            SoundChunker {
+                frame_samples: frame_samples,
+                channels: channels,
+                pending: vec![],
+                pending_pts: 0,
+            }
+        }
+
+        // pts where the pending sound ends and the next sound is expected
+        pub fn next_pts(&self) -> i64 {
+            self.pending_pts + (self.pending.len() / self.channels) as i64
+        }
+
+        // drops whatever is pending, the next sound starting at pts
+        pub fn start_at(&mut self, pts: i64) {
+            self.pending.clear();
+            self.pending_pts = pts;
+        }
+
+        // where sound is put in
+        pub fn pending_mut(&mut self) -> &mut Vec<i16> {
+            &mut self.pending
+        }
+
+        // Hands every whole frame to f along with its pts, keeping the rest for the next call
+        pub fn drain_frames<F>(&mut self, mut f: F) -> Result<(), String>
+        where F: FnMut(&[i16], i64) -> Result<(), String> {
+            let frame_len = self.frame_samples * self.channels;
+            let whole = self.pending.len() / frame_len * frame_len;
+            for start in (0..whole).step_by(frame_len) {
+                let pts = self.pending_pts;
+                self.pending_pts += self.frame_samples as i64;
+                f(&self.pending[start..start + frame_len], pts)?;
+            }
+            self.pending.drain(..whole);
+            Ok(())
+        }
+
+        // Hands what is pending to f as a frame padded with silence, if anything is
+        pub fn flush<F>(&mut self, f: F) -> Result<(), String>
+        where F: FnOnce(&[i16], i64) -> Result<(), String> {
+            if self.pending.is_empty() {
+                return Ok(());
+            }
+
+            self.pending.resize(self.frame_samples * self.channels, 0);
+            let pts = self.pending_pts;
+            self.pending_pts += self.frame_samples as i64;
+            let result = f(&self.pending, pts);
+            self.pending.clear();
+            result
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn collect(frames: &mut Vec<(i64, Vec<i16>)>) -> impl FnMut(&[i16], i64) -> Result<(), String> + '_ {
+            move |samples, pts| {
+                frames.push((pts, samples.to_vec()));
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn frames_are_cut_every_frame_samples() {
+            // 20 ms of stereo at 48 kHz
+            let mut chunker = SoundChunker::new(960, 2);
+            chunker.start_at(4800);
+            let src: Vec<i16> = (0..6000).map(|i| i as i16).collect();
+
+            chunker.pending_mut().extend_from_slice(&src[..5000]);
+            let mut frames = vec![];
+            chunker.drain_frames(collect(&mut frames)).unwrap();
+            assert_eq!(frames.iter().map(|(pts, _)| *pts).collect::<Vec<_>>(), vec![4800, 5760]);
+            assert_eq!(frames[1].1, &src[1920..3840]);
+            assert_eq!(chunker.next_pts(), 4800 + 2500);
+
+            // what was left over starts the next frame
+            chunker.pending_mut().extend_from_slice(&src[5000..]);
+            frames.clear();
+            chunker.drain_frames(collect(&mut frames)).unwrap();
+            assert_eq!(frames, vec![(6720, src[3840..5760].to_vec())]);
+
+            frames.clear();
+            chunker.flush(collect(&mut frames)).unwrap();
+            let mut padded = src[5760..].to_vec();
+            padded.resize(1920, 0);
+            assert_eq!(frames, vec![(7680, padded)]);
+            assert_eq!(chunker.next_pts(), 8640);
         }
     }
 }
@@ -72,4 +299,9 @@ pub mod time {
     pub fn now_utc() -> Duration {
         SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
     }
+
+    // pts in the timebase of 1/fps
+    pub fn to_pts(timestamp: Duration, fps: usize) -> i64 {
+        (timestamp.as_secs_f64() * fps as f64).round() as i64
+    }
 }
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use libemu::{EmuInputEvent, InputKind};
+
+const PLAYERS: u8 = 4;
+const BUTTONS_PER_PLAYER: u8 = 8;
+
+// MAME's default keyboard layout for the controls which are not player buttons.
+// Key codes are MAME's input item ids (ITEM_ID_*).
+const DEFAULT_KEYS: [(&str, u8); 25] = [
+    ("P1_UP", 77), ("P1_DOWN", 78), ("P1_LEFT", 75), ("P1_RIGHT", 76),
+    ("P2_UP", 18), ("P2_DOWN", 6), ("P2_LEFT", 4), ("P2_RIGHT", 7),
+    ("P3_UP", 9), ("P3_DOWN", 11), ("P3_LEFT", 10), ("P3_RIGHT", 12),
+    ("P4_UP", 87), ("P4_DOWN", 81), ("P4_LEFT", 83), ("P4_RIGHT", 85),
+    ("START1", 28), ("START2", 29), ("START3", 30), ("START4", 31),
+    ("COIN1", 32), ("COIN2", 33), ("COIN3", 34), ("COIN4", 35),
+    ("SERVICE1", 36),
+];
+
+// RetroPad buttons (RETRO_DEVICE_ID_JOYPAD_*) of the controls of every player with libretro,
+// where a coin is the SELECT button
+const RETROPAD_BUTTONS: [(&str, u8); 6] = [
+    ("UP", 4), ("DOWN", 5), ("LEFT", 6), ("RIGHT", 7), ("START", 3), ("COIN", 2),
+];
+// B, A, Y, X, L, R, L2, R2
+const RETROPAD_ACTION_BUTTONS: [u8; BUTTONS_PER_PLAYER as usize] = [0, 8, 1, 9, 10, 11, 12, 13];
+
+// What a logical control presses in the emulator, in a profile either
+// { "key": 32 } or { "player": 0, "button": 2 }
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(untagged)]
+enum InputTarget {
+    Key { key: u8 },
+    Button { player: u8, button: u8 },
+}
+
+// Translates logical controls (P1_UP, P1_BUTTON1, COIN1, START1, ...) into emulator inputs.
+// Profiles in the profile directory override the defaults, default.json for every system
+// and <system name>.json for a single one on top of it:
+// {
+//   "P1_BUTTON1": { "player": 0, "button": 2 },
+//   "COIN1": { "key": 34 }
+// }
+#[derive(Debug, Clone)]
+pub struct InputMap {
+    targets: HashMap<String, InputTarget>,
+}
+
+impl InputMap {
+    pub fn defaults(emulator: &str) -> InputMap {
+        if emulator == "libretro" {
+            return InputMap::retropad_defaults();
+        }
+
+        let mut targets = HashMap::new();
+        for (control, key) in DEFAULT_KEYS.iter() {
+            targets.insert(String::from(*control), InputTarget::Key { key: *key });
+        }
+        for player in 0..PLAYERS {
+            for button in 0..BUTTONS_PER_PLAYER {
+                targets.insert(
+                    format!("P{}_BUTTON{}", player + 1, button + 1),
+                    InputTarget::Button { player: player, button: button });
+            }
+        }
+
+        InputMap {
+            targets: targets,
+        }
+    }
+
+    fn retropad_defaults() -> InputMap {
+        let mut targets = HashMap::new();
+        for player in 0..PLAYERS {
+            for (control, button) in RETROPAD_BUTTONS.iter() {
+                let target = InputTarget::Button { player: player, button: *button };
+                match *control {
+                    "START" | "COIN" => targets.insert(format!("{}{}", control, player + 1), target),
+                    _ => targets.insert(format!("P{}_{}", player + 1, control), target),
+                };
+            }
+            for (i, button) in RETROPAD_ACTION_BUTTONS.iter().enumerate() {
+                targets.insert(
+                    format!("P{}_BUTTON{}", player + 1, i + 1),
+                    InputTarget::Button { player: player, button: *button });
+            }
+        }
+
+        InputMap {
+            targets: targets,
+        }
+    }
+
+    pub fn load(emulator: &str, profile_dir: Option<&str>, system_name: &str) -> Result<InputMap, String> {
+        let mut input_map = InputMap::defaults(emulator);
+        if let Some(dir) = profile_dir {
+            // the name comes from commands and must not lead out of the profile directory
+            if system_name.is_empty() || !system_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("invalid system name for an input profile: {:?}", system_name));
+            }
+            for name in ["default", system_name].iter() {
+                let path = Path::new(dir).join(format!("{}.json", name));
+                if path.is_file() {
+                    input_map.overlay(&path)?;
+                }
+            }
+        }
+        Ok(input_map)
+    }
+
+    fn overlay(&mut self, path: &Path) -> Result<(), String> {
+        let json_str = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read input profile {:?}: {}", path, e))?;
+        let profile: HashMap<String, InputTarget> = serde_json::from_str(&json_str)
+            .map_err(|e| format!("invalid input profile {:?}: {}", path, e))?;
+        self.targets.extend(profile);
+        Ok(())
+    }
+
+    pub fn translate(&self, control: &str, pressed: bool) -> Result<EmuInputEvent, String> {
+        match self.targets.get(control) {
+            Some(InputTarget::Key { key }) => Ok(EmuInputEvent::key(
+                *key,
+                if pressed { InputKind::INPUT_KEY_DOWN } else { InputKind::INPUT_KEY_UP })),
+            Some(InputTarget::Button { player, button }) => Ok(EmuInputEvent::button(
+                *player,
+                *button,
+                if pressed { InputKind::INPUT_BUTTON_DOWN } else { InputKind::INPUT_BUTTON_UP })),
+            None => Err(format!("no mapping for control: {}", control)),
+        }
+    }
+
+    // control without the player prefix (ex, BUTTON1 for P2_BUTTON1 of player 1),
+    // falling back to controls of no player in particular (ex, COIN1)
+    pub fn translate_for_player(&self, player: u8, control: &str, pressed: bool) -> Result<EmuInputEvent, String> {
+        let player_control = format!("P{}_{}", player as usize + 1, control);
+        if self.targets.contains_key(&player_control) {
+            self.translate(&player_control, pressed)
+        } else {
+            self.translate(control, pressed)
+        }
+    }
+}
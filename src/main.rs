@@ -1,19 +1,33 @@
 mod roms;
+mod memwatch;
+mod options;
+mod inputmap;
 
 use std::io::{Read, Write};
-use std::{env, thread, str, process};
+use std::{env, fs, thread, str, process};
+use std::sync::{Arc, Mutex};
 
 use nanomsg::{Socket, Protocol};
 use crossbeam_channel as channel;
 use serde::{Deserialize};
 
-use libemu::Emulator;
 use libenc::Encoder;
 
 use crate::roms::RomManager;
 
 const CHANNEL_BUF_SIZE: usize = 64;
 
+// exit code when the system is unknown or its rom set can't run
+const EXIT_CODE_NOT_PLAYABLE: i32 = 2;
+// exit code when the emulator library can't be loaded
+const EXIT_CODE_EMULATOR_UNAVAILABLE: i32 = 3;
+
+// mame looks for roms here unless rompath is given
+const DEFAULT_ROMPATH: &str = "./roms";
+
+// how far back a rewind command goes unless told otherwise
+const DEFAULT_REWIND_SECONDS: f64 = 10.0;
+
 #[derive(Debug, Default, Copy, Clone)]
 struct Resolution {
     w: usize,
@@ -26,15 +40,36 @@ impl Resolution {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+struct VideoSettings {
+    resolution: Resolution,
+    fps: usize,
+}
+
 #[derive(Debug, Default)]
 struct GameProperties {
     resolution: Resolution,
     fps: usize,
     keyframe_interval: usize,
+    audio_frame_ms: usize,
+    audio_channels: usize,
+    emulator: String,
+    mame_lib: String,
+    libretro_core: Option<String>,
     system_name: String,
     imageframe_output: String,
     soundframe_output: String,
     cmd_input: String,
+    report_output: String,
+    mem_access: memwatch::MemAccess,
+    query_system: bool,
+    emu_options: libemu::EmulatorOptions,
+    options_file: Option<String>,
+    rom_source: Option<String>,
+    input_profiles: Option<String>,
+    rewind: Option<libemu::RewindSettings>,
+    record_input: Option<String>,
+    replay_input: Option<String>,
 }
 
 fn parse_resolution(arg: String) -> (usize, usize) {
@@ -45,6 +80,15 @@ fn parse_resolution(arg: String) -> (usize, usize) {
     (whs[0], whs[1])
 }
 
+// mono / stereo, or the number of channels
+fn parse_audio_channels(arg: &str) -> Result<usize, String> {
+    match arg {
+        "mono" => Ok(1),
+        "stereo" => Ok(2),
+        _ => arg.parse().map_err(|e| format!("invalid audio channels {}: {}", arg, e)),
+    }
+}
+
 fn extract_properties_from_args(args: &Vec<String>) -> GameProperties {
     let mut props = GameProperties::default();
 
@@ -52,9 +96,14 @@ fn extract_properties_from_args(args: &Vec<String>) -> GameProperties {
     props.resolution = Resolution::from_size(480, 320);
     props.fps = 30;
     props.keyframe_interval = 12;
+    props.audio_frame_ms = 20;
+    props.audio_channels = 2;
+    props.emulator = String::from("mame");
+    props.mame_lib = String::from(libemu::DEFAULT_MAME_LIB_PATH);
     props.imageframe_output = String::from("ipc://./images.ipc");
     props.soundframe_output = String::from("ipc://./sounds.ipc");
     props.cmd_input = String::from("ipc://./cmds.ipc");
+    props.report_output = String::from("ipc://./reports.ipc");
 
     for (i, arg) in args.iter().map(|s| s.as_str()).enumerate() {
         let next_arg = || { args[i+1].clone() };
@@ -62,6 +111,15 @@ fn extract_properties_from_args(args: &Vec<String>) -> GameProperties {
             "--game" => {
                 props.system_name = next_arg()
             }
+            "--emulator" => {
+                props.emulator = next_arg()
+            },
+            "--mame-lib" => {
+                props.mame_lib = next_arg()
+            },
+            "--libretro-core" => {
+                props.libretro_core = Some(next_arg())
+            },
             "--imageframe-output" => {
                 props.imageframe_output = next_arg()
             },
@@ -71,12 +129,76 @@ fn extract_properties_from_args(args: &Vec<String>) -> GameProperties {
             "--cmd-input" => {
                 props.cmd_input = next_arg()
             },
+            "--report-output" => {
+                props.report_output = next_arg()
+            },
+            "--mem-access" => {
+                props.mem_access = memwatch::MemAccess::parse(&next_arg()).unwrap()
+            },
+            "--rompath" => {
+                props.emu_options.rompath = Some(next_arg())
+            },
+            "--nvram-dir" => {
+                props.emu_options.nvram_directory = Some(next_arg())
+            },
+            "--cfg-dir" => {
+                props.emu_options.cfg_directory = Some(next_arg())
+            },
+            "--state-dir" => {
+                props.emu_options.state_directory = Some(next_arg())
+            },
+            "--bgfx-chain" => {
+                props.emu_options.bgfx_screen_chains = Some(next_arg())
+            },
+            "--video-backend" => {
+                props.emu_options.video = Some(next_arg())
+            },
+            "--sample-rate" => {
+                props.emu_options.sample_rate = Some(next_arg().parse().unwrap())
+            },
+            "--run-ahead" => {
+                props.emu_options.run_ahead = Some(next_arg().parse().unwrap())
+            },
+            "--mame-option" => {
+                props.emu_options.extra.push(options::parse_extra_option(&next_arg()).unwrap())
+            },
+            "--options-file" => {
+                props.options_file = Some(next_arg())
+            },
+            "--rom-source" => {
+                props.rom_source = Some(next_arg())
+            },
+            "--input-profiles" => {
+                props.input_profiles = Some(next_arg())
+            },
+            "--rewind-interval" => {
+                props.rewind.get_or_insert_with(Default::default).interval = next_arg().parse().unwrap()
+            },
+            "--rewind-memory" => {
+                let megabytes: usize = next_arg().parse().unwrap();
+                props.rewind.get_or_insert_with(Default::default).memory_limit = megabytes * 1024 * 1024
+            },
+            "--query-system" => {
+                props.query_system = true
+            },
+            "--record-input" => {
+                props.record_input = Some(next_arg())
+            },
+            "--replay-input" => {
+                props.replay_input = Some(next_arg())
+            },
             "--fps" => {
                 props.fps = next_arg().parse().unwrap()
             },
             "--keyframe-interval" => {
                 props.keyframe_interval = next_arg().parse().unwrap()
             },
+            "--audio-frame-ms" => {
+                props.audio_frame_ms = next_arg().parse().unwrap()
+            },
+            "--audio-channels" => {
+                props.audio_channels = parse_audio_channels(&next_arg()).unwrap()
+            },
             "--resolution" => {
                 let (w, h) = parse_resolution(next_arg());
                 props.resolution = Resolution::from_size(w, h);
@@ -95,8 +217,10 @@ fn extract_properties_from_args(args: &Vec<String>) -> GameProperties {
 fn run_frame_encoder(
     props: &GameProperties,
     encoder_rx: channel::Receiver<libemu::EmuImageFrame>,
+    settings_rx: channel::Receiver<VideoSettings>,
     frame_tx: channel::Sender<libenc::EncodedFrame>) {
 
+    let keyframe_interval = props.keyframe_interval;
     let mut vid_enc = libenc::H264Encoder::create(
         props.resolution.w,
         props.resolution.h,
@@ -108,7 +232,27 @@ fn run_frame_encoder(
             let raw_frame = encoder_rx.recv().unwrap();
             // println!("raw frame size: {}", raw_frame.buf.len());
 
-            let frame = libenc::VideoFrame::from(&raw_frame.buf, raw_frame.timestamp);
+            // rebuild the encoder on renegotiation, so the new stream starts with a keyframe
+            for settings in settings_rx.try_iter() {
+                vid_enc = libenc::H264Encoder::create(
+                    settings.resolution.w, settings.resolution.h, settings.fps, keyframe_interval);
+            }
+            // the picture starts over after a reset or a system switch
+            if raw_frame.discontinuity {
+                vid_enc.force_keyframe();
+            }
+
+            let layout = libenc::ImageLayout {
+                width: raw_frame.width,
+                height: raw_frame.height,
+                stride: raw_frame.stride,
+                format: match raw_frame.format {
+                    libemu::PixelFormat::PIXEL_FORMAT_BGRA32 => libenc::PixelFormat::BGRA32,
+                    libemu::PixelFormat::PIXEL_FORMAT_RGBA32 => libenc::PixelFormat::RGBA32,
+                },
+            };
+            let frame = libenc::VideoFrame::from(
+                raw_frame.buf, layout, raw_frame.frame_number, raw_frame.timestamp, raw_frame.emu_timestamp);
             match vid_enc.encode_video(&frame) {
                 Ok(encoded) => {
                     frame_tx.send(encoded).unwrap();
@@ -145,7 +289,7 @@ fn run_sound_encoder(
     encoder_rx: channel::Receiver<libemu::EmuSoundFrame>,
     frame_tx: channel::Sender<libenc::EncodedFrame>) {
 
-    let mut opus_enc = libenc::OpusEncoder::create(props.fps);
+    let mut opus_enc = libenc::OpusEncoder::create(props.audio_frame_ms, props.audio_channels).unwrap();
 
     thread::spawn(move || {
         loop {
@@ -153,11 +297,14 @@ fn run_sound_encoder(
             // println!("raw sound size: {}", raw_frame.buf.len());
 
             let frame = libenc::AudioFrame::from(
-                &raw_frame.buf, raw_frame.timestamp, raw_frame.samples, raw_frame.sample_rate);
+                raw_frame.buf, raw_frame.timestamp, raw_frame.emu_timestamp,
+                raw_frame.samples, raw_frame.sample_rate, raw_frame.channels);
 
             match opus_enc.encode_audio(&frame) {
                 Ok(encoded) => {
-                    frame_tx.send(encoded).unwrap();
+                    for encoded in encoded {
+                        frame_tx.send(encoded).unwrap();
+                    }
                 },
                 Err(_) => {
                     // println!("{}", msg);
@@ -188,44 +335,352 @@ fn run_sound_handler(
 // Command Specification
 // 'key'
 //   - args[0]: string of key input (ex, 053d) 
+// 'btn'
+//   - args[0]: logical control (ex, P1_UP / P1_BUTTON1 / COIN1 / START1), mapped to an emulator input
+//              by the input profiles of the game
+//   - args[1]: d / u
+// 'input'
+//   - args[0]: player index starting from 0
+//   - args[1]: input type (button / axis / axis_rel / pointer)
+//   - args[2..]: button index and d/u for button (ex, 2 d),
+//                axis index and position in [-65536, 65536] for axis (ex, 0 -32768),
+//                axis index and delta for axis_rel (ex, 1 12),
+//                x and y in image frame pixels for pointer (ex, 120 88)
 // 'ctrl'
-//   - args[0]: string for stream control (ex, pause / resume / shutdown)
+//   - args[0]: string for stream control
+//              (ex, pause / resume / shutdown / save_state / load_state / video / speed / throttle / step /
+//              reset / load / rewind)
+//   - args[1]: slot name for save_state / load_state (ex, slot1), resolution for video (ex, 320x240),
+//              speed multiplier for speed (ex, 0.5), on / off for throttle, soft / hard for reset (default soft),
+//              system name for load (ex, sf2), seconds to go back for rewind (default 10, needs --rewind-memory
+//              or --rewind-interval)
+//   - args[2]: fps for video (ex, 15)
+//   step advances a single frame while paused. load_state, reset, load, rewind and video are refused while
+//   recording input.
+// 'start' (only when standing by, started without --game)
+//   - args[0]: system name (ex, dino)
+//   - args[1]: resolution (ex, 480x320), optional along with fps
+//   - args[2]: fps (ex, 23), both default to the command line
+// 'turbo'
+//   - args[0]: player index starting from 0
+//   - args[1]: button index
+//   - args[2]: presses per second while the button is held, 0 turns turbo off (ex, 15)
+// 'macro'
+//   - args[0]: set / run / clear
+//   - args[1]: player index starting from 0
+//   - args[2]: macro name (ex, hadouken)
+//   - args[3..]: steps for set as <frame offset>:<control>:<d/u>, the control being a logical control
+//                without the player prefix (ex, 0:DOWN:d 4:RIGHT:d 4:DOWN:u 8:BUTTON1:d 8:RIGHT:u 12:BUTTON1:u)
+// 'mem' (only with --mem-access read / write)
+//   - args[0]: read / write / watch / unwatch
+//   - args[1..]: cpu tag, address space (program / data / io / opcodes), address and width in bytes
+//                for read (ex, :maincpu program 0xff8000 2), followed by the value for write,
+//                watch id, location and optional interval in ms for watch (ex, score :maincpu program 0xff8000 2 100),
+//                watch id for unwatch. Read values and watched value changes go to the report output.
 #[derive(Deserialize, Debug)]
 struct Command {
   cmd: String,
   args: Vec<String>,
 }
 
+#[derive(Debug)]
+struct StartSettings {
+    system_name: String,
+    video: VideoSettings,
+}
+
+fn parse_input_event(args: &Vec<String>) -> Result<libemu::EmuInputEvent, String> {
+    if args.len() < 4 {
+        return Err(format!("invalid input args: {:?}", args));
+    }
+
+    let parse_num = |s: &str| s.parse::<i32>().map_err(|e| format!("invalid input arg {}: {}", s, e));
+    let parse_idx = |s: &str| s.parse::<u8>().map_err(|e| format!("invalid input arg {}: {}", s, e));
+
+    let player = parse_idx(&args[0])?;
+    match &args[1][..] {
+        "button" => {
+            let kind = match &args[3][..] {
+                "u" => libemu::InputKind::INPUT_BUTTON_UP,
+                _ => libemu::InputKind::INPUT_BUTTON_DOWN,
+            };
+            Ok(libemu::EmuInputEvent::button(player, parse_idx(&args[2])?, kind))
+        },
+        "axis" => Ok(libemu::EmuInputEvent::axis(
+            player, parse_idx(&args[2])?, libemu::InputKind::INPUT_AXIS_ABSOLUTE, parse_num(&args[3])?)),
+        "axis_rel" => Ok(libemu::EmuInputEvent::axis(
+            player, parse_idx(&args[2])?, libemu::InputKind::INPUT_AXIS_RELATIVE, parse_num(&args[3])?)),
+        "pointer" => Ok(libemu::EmuInputEvent::pointer(
+            player, parse_num(&args[2])?, parse_num(&args[3])?)),
+        _ => Err(format!("not supported input type: {}", args[1])),
+    }
+}
+
+fn parse_macro_step(input_map: &inputmap::InputMap, player: u8, step: &str) -> Result<libemu::MacroStep, String> {
+    let fields: Vec<&str> = step.split(':').collect();
+    match fields[..] {
+        [offset, control, state] => Ok(libemu::MacroStep {
+            offset: offset.parse().map_err(|e| format!("invalid macro step {}: {}", step, e))?,
+            event: input_map.translate_for_player(player, control, state != "u")?,
+        }),
+        _ => Err(format!("invalid macro step: {}", step)),
+    }
+}
+
+fn parse_video_settings(args: &Vec<String>) -> Result<VideoSettings, String> {
+    if args.len() < 3 {
+        return Err(format!("invalid video args: {:?}", args));
+    }
+
+    let whs: Vec<usize> = args[1].split("x")
+        .filter_map(|s| s.parse().ok())
+        .collect();
+    let fps: usize = args[2].parse().map_err(|e| format!("invalid fps {}: {}", args[2], e))?;
+    match whs[..] {
+        [w, h] if w > 0 && h > 0 && fps > 0 => Ok(VideoSettings {
+            resolution: Resolution::from_size(w, h),
+            fps: fps,
+        }),
+        _ => Err(format!("invalid video settings: {}, {}", args[1], args[2])),
+    }
+}
+
+fn parse_start_settings(args: &Vec<String>, default_video: VideoSettings) -> Result<StartSettings, String> {
+    let video = match args.len() {
+        0 => return Err(format!("system name is missing for start")),
+        1 => default_video,
+        _ => parse_video_settings(args)?,
+    };
+    Ok(StartSettings {
+        system_name: args[0].clone(),
+        video: video,
+    })
+}
+
+fn run_report_handler(
+    props: &GameProperties,
+    report_rx: channel::Receiver<memwatch::MemoryReport>) {
+
+    let report_output_path = String::from(&props.report_output);
+
+    thread::spawn(move || {
+        let mut socket = Socket::new(Protocol::Push).unwrap();
+        socket.bind(&report_output_path).unwrap();
+
+        loop {
+            let report = report_rx.recv().unwrap();
+            let json_str = serde_json::to_string(&report).unwrap();
+            socket.write_all(json_str.as_bytes()).unwrap();
+        }
+    });
+}
+
 fn run_cmd_handler(
     props: &GameProperties,
-    emu: (impl libemu::Emulator + Send + 'static)) {
+    emu: (impl libemu::Emulator + Send + 'static),
+    video_settings_tx: channel::Sender<VideoSettings>,
+    mem_handler: memwatch::MemCmdHandler,
+    start_tx: channel::Sender<StartSettings>,
+    loader: SystemLoader) {
 
     let cmd_input_path = String::from(&props.cmd_input);
+    let recording = props.record_input.is_some();
+    let default_video = VideoSettings {
+        resolution: props.resolution,
+        fps: props.fps,
+    };
 
     thread::spawn(move || {
+        let mut video_emu = emu.clone();
+        let mut handle_cmd_video = |args: &Vec<String>| {
+            match parse_video_settings(args) {
+                Ok(settings) => {
+                    // let the encoder switch first, so that frames of the new size are not dropped
+                    video_settings_tx.send(settings).unwrap();
+                    video_emu.set_image_frame_info(settings.resolution.w, settings.resolution.h, settings.fps);
+                },
+                Err(msg) => println!("{}", msg),
+            }
+        };
+
         let handle_cmd_key = |args: &Vec<String>| {
             // parse buf and put input to emu
             let input_str = &args[0];
-            emu.put_input_event(libemu::EmuInputEvent {
-                value: input_str[0..3].parse::<u8>().unwrap(),
-                kind: match &input_str[3..] {
+            emu.put_input_event(libemu::EmuInputEvent::key(
+                input_str[0..3].parse::<u8>().unwrap(),
+                match &input_str[3..] {
                     "d" => libemu::InputKind::INPUT_KEY_DOWN,
                     "u" => libemu::InputKind::INPUT_KEY_UP,
                     _ => libemu::InputKind::INPUT_KEY_DOWN,
                 }
-            });
+            ));
+        };
+
+        let handle_cmd_btn = |args: &Vec<String>| {
+            let control = match args.get(0) {
+                Some(control) => control,
+                None => return println!("control is missing for btn"),
+            };
+            let pressed = args.get(1).map_or(true, |s| s != "u");
+            match loader.input_map.lock().unwrap().translate(control, pressed) {
+                Ok(event) => emu.put_input_event(event),
+                Err(msg) => println!("{}", msg),
+            }
+        };
+
+        let handle_cmd_turbo = |args: &Vec<String>| {
+            if args.len() < 3 {
+                return println!("invalid turbo args: {:?}", args);
+            }
+            let result = match (args[0].parse::<u8>(), args[1].parse::<u8>(), args[2].parse::<f64>()) {
+                (Ok(player), Ok(button), Ok(rate)) => emu.set_turbo(player, button, rate),
+                _ => Err(format!("invalid turbo args: {:?}", args)),
+            };
+            if let Err(msg) = result {
+                println!("{}", msg);
+            }
+        };
+
+        // steps are resolved by the input map of the moment the macro is set
+        let handle_cmd_macro = |args: &Vec<String>| {
+            if args.len() < 3 {
+                return println!("invalid macro args: {:?}", args);
+            }
+            let player = match args[1].parse::<u8>() {
+                Ok(player) => player,
+                Err(e) => return println!("invalid player {}: {}", args[1], e),
+            };
+            let name = &args[2];
+            let result = match &args[0][..] {
+                "set" => {
+                    let input_map = loader.input_map.lock().unwrap();
+                    args[3..].iter()
+                        .map(|step| parse_macro_step(&input_map, player, step))
+                        .collect::<Result<Vec<_>, String>>()
+                        .map(|steps| emu.set_macro(player, name, steps))
+                },
+                "run" => emu.run_macro(player, name),
+                "clear" => Ok(emu.set_macro(player, name, vec![])),
+                _ => Err(format!("not supported macro op: {}", args[0])),
+            };
+            if let Err(msg) = result {
+                println!("{}", msg);
+            }
+        };
+
+        let handle_cmd_input = |args: &Vec<String>| {
+            match parse_input_event(args) {
+                Ok(event) => emu.put_input_event(event),
+                Err(msg) => println!("{}", msg),
+            }
+        };
+
+        let handle_cmd_state = |ctrl_val: &str, args: &Vec<String>| {
+            let slot_name = match args.get(1) {
+                Some(slot_name) => slot_name,
+                None => return println!("slot name is missing for {}", ctrl_val),
+            };
+            let result = match ctrl_val {
+                "save_state" => emu.save_state(slot_name),
+                _ => emu.load_state(slot_name),
+            };
+            if let Err(msg) = result {
+                println!("{}", msg);
+            }
         };
 
-        let handle_cmd_ctrl = |args: &Vec<String>| {
+        let handle_cmd_speed = |ctrl_val: &str, args: &Vec<String>| {
+            let val = match args.get(1) {
+                Some(val) => val,
+                None => return println!("value is missing for {}", ctrl_val),
+            };
+            let result = match ctrl_val {
+                "speed" => match val.parse::<f64>() {
+                    Ok(speed) => emu.set_speed(speed),
+                    Err(e) => Err(format!("invalid speed {}: {}", val, e)),
+                },
+                _ => match &val[..] {
+                    "on" => Ok(emu.set_throttled(true)),
+                    "off" => Ok(emu.set_throttled(false)),
+                    _ => Err(format!("invalid throttle value: {}", val)),
+                },
+            };
+            if let Err(msg) = result {
+                println!("{}", msg);
+            }
+        };
+
+        let handle_cmd_reset = |args: &Vec<String>| {
+            match args.get(1).map(|s| s.as_str()) {
+                None | Some("soft") => emu.reset(libemu::ResetKind::RESET_SOFT),
+                Some("hard") => emu.reset(libemu::ResetKind::RESET_HARD),
+                Some(val) => println!("invalid reset value: {}", val),
+            }
+        };
+
+        // the streams carry on from the restored snapshot, starting with a keyframe
+        let handle_cmd_rewind = |args: &Vec<String>| {
+            let result = match args.get(1) {
+                Some(val) => val.parse::<f64>()
+                    .map_err(|e| format!("invalid rewind seconds {}: {}", val, e))
+                    .and_then(|seconds| emu.rewind(seconds)),
+                None => emu.rewind(DEFAULT_REWIND_SECONDS),
+            };
+            if let Err(msg) = result {
+                println!("{}", msg);
+            }
+        };
+
+        // the encoders keep running, the first frame of the new system comes out as a keyframe
+        let mut load_emu = emu.clone();
+        let mut handle_cmd_load = |args: &Vec<String>| {
+            let system_name = match args.get(1) {
+                Some(system_name) => system_name,
+                None => return println!("system name is missing for load"),
+            };
+            let result = loader.prepare(&mut load_emu, system_name)
+                .and_then(|_| load_emu.load_system(system_name));
+            if let Err(msg) = result {
+                println!("{}", msg);
+            }
+        };
+
+        let mut handle_cmd_ctrl = |args: &Vec<String>| {
             let ctrl_val = &args[0];
             match &ctrl_val[..] {
                 "pause" => emu.pause(),
                 "resume" => emu.resume(),
-                "shutdown" => process::exit(0),
+                // a replay has no record of these, so it couldn't be played back. video would change the
+                // resolution and fps the replay header was written with
+                "load_state" | "reset" | "load" | "rewind" | "video" if recording => println!("{} is not allowed while recording input", ctrl_val),
+                "save_state" | "load_state" => handle_cmd_state(ctrl_val, args),
+                "video" => handle_cmd_video(args),
+                "speed" | "throttle" => handle_cmd_speed(ctrl_val, args),
+                "step" => if let Err(msg) = emu.step_frame() { println!("{}", msg) },
+                "reset" => handle_cmd_reset(args),
+                "load" => handle_cmd_load(args),
+                "rewind" => handle_cmd_rewind(args),
+                "shutdown" => emu.stop(),
                 _ => println!("ctrl val: {}", &args[0]),
             }
         };
 
+        let handle_cmd_mem = |args: &Vec<String>| {
+            if let Err(msg) = mem_handler.handle(&emu, args) {
+                println!("{}", msg);
+            }
+        };
+
+        // the start itself is up to the main thread, which stops listening once started
+        let handle_cmd_start = |args: &Vec<String>| {
+            match parse_start_settings(args, default_video) {
+                Ok(start) => if start_tx.send(start).is_err() {
+                    println!("a system is already running");
+                },
+                Err(msg) => println!("{}", msg),
+            }
+        };
+
         // connect to cmd input queue, then polling and handling cmd
         let mut socket = Socket::new(Protocol::Pull).unwrap();
         socket.bind(&cmd_input_path).unwrap();
@@ -238,38 +693,319 @@ fn run_cmd_handler(
 
             match &command.cmd[..] {
                 "key" => handle_cmd_key(&command.args),
+                "btn" => handle_cmd_btn(&command.args),
+                "turbo" => handle_cmd_turbo(&command.args),
+                "macro" => handle_cmd_macro(&command.args),
+                "input" => handle_cmd_input(&command.args),
                 "ctrl" => handle_cmd_ctrl(&command.args),
+                "mem" => handle_cmd_mem(&command.args),
+                "start" => handle_cmd_start(&command.args),
                 _ => println!("not supported cmd"),
             }
         };
     });
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let props = extract_properties_from_args(&args);
+// Gets a system ready to run, at launch or when switching to it: applies its options,
+// fetches its roms when a rom source is given, audits them and picks its input profile.
+// Clones share the input map.
+#[derive(Clone)]
+struct SystemLoader {
+    emulator: String,
+    rom_source: Option<String>,
+    options_file: Option<String>,
+    cli_options: libemu::EmulatorOptions,
+    input_profiles: Option<String>,
+    input_map: Arc<Mutex<inputmap::InputMap>>,
+}
 
-    // let mut rom_manager = roms::AwsRomManager::create("./roms");
-    // rom_manager.pull_roms("mame", &props.system_name).unwrap();
+impl SystemLoader {
+    fn from(props: &GameProperties) -> SystemLoader {
+        SystemLoader {
+            emulator: props.emulator.clone(),
+            rom_source: props.rom_source.clone(),
+            options_file: props.options_file.clone(),
+            cli_options: props.emu_options.clone(),
+            input_profiles: props.input_profiles.clone(),
+            input_map: Arc::new(Mutex::new(inputmap::InputMap::defaults(&props.emulator))),
+        }
+    }
 
-    let mut emu = libemu::MameEmulator::create(
-        props.resolution.w,
-        props.resolution.h,
-        props.fps);
+    // options given on the command line take precedence over the options file
+    fn options(&self, system_name: &str) -> Result<libemu::EmulatorOptions, String> {
+        match &self.options_file {
+            Some(path) => Ok(options::load_options(path, system_name)?.overlay(&self.cli_options)),
+            None => Ok(self.cli_options.clone()),
+        }
+    }
+
+    fn pull_roms(&self, rompath: &str, system_name: &str) -> Result<(), String> {
+        let rom_source = match &self.rom_source {
+            Some(rom_source) => rom_source,
+            None => return Ok(()),
+        };
+
+        fs::create_dir_all(rompath).map_err(|e| format!("failed to create {}: {}", rompath, e))?;
+        match &rom_source[..] {
+            "aws" => roms::AwsRomManager::create(rompath).pull_roms(&self.emulator, system_name),
+            "gcp" => roms::GcpRomManager::create(rompath).pull_roms(&self.emulator, system_name),
+            _ => Err(format!("invalid rom source: {}", rom_source)),
+        }
+    }
+
+    fn prepare(&self, emu: &mut impl libemu::Emulator, system_name: &str) -> Result<(), String> {
+        let input_map = inputmap::InputMap::load(&self.emulator, self.input_profiles.as_deref(), system_name)?;
+        let options = self.options(system_name)?;
+        // rompath may list several directories separated by ';', roms are fetched to the first
+        let rompath = options.rompath.as_ref()
+            .and_then(|rompath| rompath.split(';').next())
+            .unwrap_or(DEFAULT_ROMPATH);
+        self.pull_roms(rompath, system_name)?;
+        emu.set_options(&options)?;
+        check_playable(emu, system_name)?;
+        *self.input_map.lock().unwrap() = input_map;
+        Ok(())
+    }
+}
+
+fn rom_status_name(status: libemu::RomStatus) -> &'static str {
+    match status {
+        libemu::RomStatus::ROM_STATUS_GOOD => "good",
+        libemu::RomStatus::ROM_STATUS_NOT_FOUND => "not_found",
+        libemu::RomStatus::ROM_STATUS_BAD_SIZE => "bad_size",
+        libemu::RomStatus::ROM_STATUS_BAD_CHECKSUM => "bad_checksum",
+        libemu::RomStatus::ROM_STATUS_NO_DUMP => "no_dump",
+    }
+}
+
+// Audits the rom set, listing the roms which keep the system from running.
+fn check_playable(emu: &impl libemu::Emulator, system_name: &str) -> Result<(), String> {
+    let audit = emu.audit_system(system_name)?;
+    if audit.is_playable() {
+        return Ok(());
+    }
+
+    for r in audit.problems() {
+        println!("rom {} of {}: {}", r.name, system_name, rom_status_name(r.status));
+    }
+    Err(format!("rom set of {} is not playable", system_name))
+}
+
+// Audits the rom set and collects the metadata of the system as json, returning whether it can run.
+fn query_system(emu: &impl libemu::Emulator, system_name: &str) -> (serde_json::Value, bool) {
+    let mut errors = vec![];
+
+    let info = match emu.system_info(system_name) {
+        Ok(info) => serde_json::json!({
+            "description": info.description,
+            "year": info.year,
+            "manufacturer": info.manufacturer,
+            "screen_width": info.screen_width,
+            "screen_height": info.screen_height,
+            "orientation": match info.orientation {
+                libemu::Orientation::ORIENTATION_ROT0 => 0,
+                libemu::Orientation::ORIENTATION_ROT90 => 90,
+                libemu::Orientation::ORIENTATION_ROT180 => 180,
+                libemu::Orientation::ORIENTATION_ROT270 => 270,
+            },
+            "refresh_rate": info.refresh_rate,
+            "players": info.players,
+            "buttons": info.buttons,
+        }),
+        Err(msg) => {
+            errors.push(msg);
+            serde_json::Value::Null
+        },
+    };
+
+    let (roms, playable) = match emu.audit_system(system_name) {
+        Ok(audit) => {
+            let roms: Vec<serde_json::Value> = audit.records.iter().map(|r| serde_json::json!({
+                "name": r.name,
+                "status": rom_status_name(r.status),
+                "expected_size": r.expected_size,
+                "actual_size": r.actual_size,
+                "optional": r.optional,
+            })).collect();
+            (roms, audit.is_playable())
+        },
+        Err(msg) => {
+            errors.push(msg);
+            (vec![], false)
+        },
+    };
+
+    let report = serde_json::json!({
+        "system": system_name,
+        "playable": playable && errors.is_empty(),
+        "info": info,
+        "roms": roms,
+        "errors": errors,
+    });
+    (report, playable && errors.is_empty())
+}
+
+fn run_emulator(
+    props: &mut GameProperties,
+    mut emu: impl libemu::Emulator + 'static,
+    replay: Option<libemu::Replay>) {
+
+    let loader = SystemLoader::from(props);
+    // without a game, the sockets and encoders are set up and wait for a start command
+    let standby = props.system_name.is_empty();
+
+    if props.query_system {
+        if standby {
+            println!("system to query is missing, give it with --game");
+            process::exit(EXIT_CODE_NOT_PLAYABLE);
+        }
+        let options = loader.options(&props.system_name);
+        if let Err(msg) = options.and_then(|options| emu.set_options(&options)) {
+            println!("{}", msg);
+            process::exit(EXIT_CODE_NOT_PLAYABLE);
+        }
+
+        let (report, playable) = query_system(&emu, &props.system_name);
+        println!("{}", report);
+        process::exit(if playable { 0 } else { EXIT_CODE_NOT_PLAYABLE });
+    }
+
+    // pre-flight check, so a bad rom set is reported instead of failing somewhere in mame
+    if !standby {
+        if let Err(msg) = loader.prepare(&mut emu, &props.system_name) {
+            println!("{}", msg);
+            process::exit(EXIT_CODE_NOT_PLAYABLE);
+        }
+    }
 
     let (img_enc_tx, img_enc_rx) = channel::bounded(CHANNEL_BUF_SIZE);
     let (img_frame_tx, img_frame_rx) = channel::bounded(CHANNEL_BUF_SIZE);
-    emu.set_image_frame_cb(|f: libemu::EmuImageFrame| { img_enc_tx.send(f).unwrap(); });
-    run_frame_encoder(&props, img_enc_rx, img_frame_tx);
-    run_frame_handler(&props, img_frame_rx);
+    let _img_cb = emu.set_image_frame_cb(move |f: libemu::EmuImageFrame| { img_enc_tx.send(f).unwrap(); });
+    let (video_settings_tx, video_settings_rx) = channel::unbounded();
+    run_frame_encoder(props, img_enc_rx, video_settings_rx, img_frame_tx);
+    run_frame_handler(props, img_frame_rx);
 
     let (snd_enc_tx, snd_enc_rx) = channel::bounded(CHANNEL_BUF_SIZE);
     let (snd_frame_tx, snd_frame_rx) = channel::bounded(CHANNEL_BUF_SIZE);
-    emu.set_sound_frame_cb(|f: libemu::EmuSoundFrame| { snd_enc_tx.send(f).unwrap(); });
-    run_sound_encoder(&props, snd_enc_rx, snd_frame_tx);
-    run_sound_handler(&props, snd_frame_rx);
+    let _snd_cb = emu.set_sound_frame_cb(move |f: libemu::EmuSoundFrame| { snd_enc_tx.send(f).unwrap(); });
+    run_sound_encoder(props, snd_enc_rx, snd_frame_tx);
+    run_sound_handler(props, snd_frame_rx);
 
-    run_cmd_handler(&props, emu.clone());
+    let (report_tx, report_rx) = channel::bounded(CHANNEL_BUF_SIZE);
+    if props.mem_access != memwatch::MemAccess::Off {
+        run_report_handler(props, report_rx);
+    }
+    let mem_handler = memwatch::MemCmdHandler::create(props.mem_access, emu.clone(), report_tx);
+
+    // a replay drives the inputs by itself, so commands are not taken at all
+    let (start_tx, start_rx) = channel::unbounded();
+    if replay.is_none() {
+        run_cmd_handler(props, emu.clone(), video_settings_tx.clone(), mem_handler, start_tx, loader.clone());
+    }
 
-    emu.run(&props.system_name);
+    if standby {
+        println!("standing by for a start command");
+        loop {
+            let start = start_rx.recv().unwrap();
+            match loader.prepare(&mut emu, &start.system_name) {
+                Ok(_) => {
+                    let video = start.video;
+                    video_settings_tx.send(video).unwrap();
+                    emu.set_image_frame_info(video.resolution.w, video.resolution.h, video.fps);
+                    props.system_name = start.system_name;
+                    props.resolution = video.resolution;
+                    props.fps = video.fps;
+                    break;
+                },
+                Err(msg) => println!("failed to start {}: {}", start.system_name, msg),
+            }
+        }
+    }
+    drop(start_rx);
+
+    if let Some(settings) = props.rewind {
+        if let Err(msg) = emu.set_rewind(Some(settings)) {
+            println!("{}", msg);
+        }
+    }
+
+    match (replay, &props.record_input) {
+        (Some(replay), _) => {
+            if replay.header.emulator_version != emu.version() {
+                println!("replay was recorded with {}, playing back with {}",
+                    replay.header.emulator_version, emu.version());
+            }
+            emu.set_input_mode(libemu::InputMode::Playback(replay));
+        },
+        (None, Some(record_path)) => {
+            let header = libemu::ReplayHeader {
+                system_name: props.system_name.clone(),
+                width: props.resolution.w,
+                height: props.resolution.h,
+                fps: props.fps,
+                emulator_version: emu.version(),
+            };
+            match libemu::ReplayWriter::create(record_path, &header) {
+                Ok(writer) => emu.set_input_mode(libemu::InputMode::Record(writer)),
+                Err(msg) => panic!("{}", msg),
+            }
+        },
+        (None, None) => {},
+    }
+
+    let handle = emu.run(&props.system_name);
+    for event in handle.events() {
+        println!("emulator event: {:?}", event);
+        match event {
+            libemu::EmuEvent::GameExited(_) | libemu::EmuEvent::Error(_) => break,
+            libemu::EmuEvent::ReplayFinished => handle.stop(),
+            _ => {}
+        }
+    }
+
+    let status = handle.wait();
+    println!("emulator exited: {:?}", status);
+    // finishes the recording, if any
+    emu.set_input_mode(libemu::InputMode::Live);
+    process::exit(status.code);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut props = extract_properties_from_args(&args);
+
+    // a replay is played back with the game and video settings it was recorded with
+    let replay = props.replay_input.clone().map(|path| {
+        let replay = libemu::Replay::load(&path).unwrap();
+        props.system_name = replay.header.system_name.clone();
+        props.resolution = Resolution::from_size(replay.header.width, replay.header.height);
+        props.fps = replay.header.fps;
+        replay
+    });
+
+    let (w, h, fps) = (props.resolution.w, props.resolution.h, props.fps);
+    match &props.emulator.clone()[..] {
+        "mame" => match libemu::MameEmulator::create(&props.mame_lib, w, h, fps) {
+            Ok(emu) => run_emulator(&mut props, emu, replay),
+            Err(msg) => {
+                println!("{}", msg);
+                process::exit(EXIT_CODE_EMULATOR_UNAVAILABLE);
+            },
+        },
+        "libretro" => match props.libretro_core.clone() {
+            Some(core_path) => match libemu::LibretroEmulator::create(&core_path, w, h, fps) {
+                Ok(emu) => run_emulator(&mut props, emu, replay),
+                Err(msg) => {
+                    println!("{}", msg);
+                    process::exit(EXIT_CODE_EMULATOR_UNAVAILABLE);
+                },
+            },
+            None => {
+                println!("--libretro-core is required with the libretro emulator");
+                process::exit(EXIT_CODE_EMULATOR_UNAVAILABLE);
+            },
+        },
+        "pattern" => run_emulator(&mut props, libemu::PatternEmulator::create(w, h, fps), replay),
+        _ => panic!("invalid emulator: {}", props.emulator),
+    }
 }
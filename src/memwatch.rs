@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel as channel;
+use serde::Serialize;
+
+use libemu::{AddressSpace, Emulator, MemoryLocation};
+
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const DEFAULT_WATCH_INTERVAL_MS: u64 = 100;
+
+// How much of the emulated memory the 'mem' commands may touch
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MemAccess {
+    Off,
+    Read,
+    ReadWrite,
+}
+
+impl MemAccess {
+    pub fn parse(val: &str) -> Result<MemAccess, String> {
+        match val {
+            "off" => Ok(MemAccess::Off),
+            "read" => Ok(MemAccess::Read),
+            "write" => Ok(MemAccess::ReadWrite),
+            _ => Err(format!("invalid memory access: {}", val)),
+        }
+    }
+}
+
+impl Default for MemAccess {
+    fn default() -> MemAccess {
+        MemAccess::Off
+    }
+}
+
+// Reported for a read command (id "read") and for every change of a watched value
+#[derive(Serialize, Debug)]
+pub struct MemoryReport {
+    pub id: String,
+    pub cpu: String,
+    pub space: String,
+    pub address: u64,
+    pub width: usize,
+    pub value: u64,
+}
+
+impl MemoryReport {
+    fn from(id: &str, location: &MemoryLocation, value: u64) -> MemoryReport {
+        MemoryReport {
+            id: String::from(id),
+            cpu: location.cpu_tag.clone(),
+            space: String::from(space_name(location.space)),
+            address: location.address,
+            width: location.width,
+            value: value,
+        }
+    }
+}
+
+enum WatchCmd {
+    Watch(String, MemoryLocation, Duration),
+    Unwatch(String),
+}
+
+struct Watch {
+    location: MemoryLocation,
+    interval: Duration,
+    next_poll: Instant,
+    last_value: Option<u64>,
+}
+
+fn space_name(space: AddressSpace) -> &'static str {
+    match space {
+        AddressSpace::ADDRESS_SPACE_PROGRAM => "program",
+        AddressSpace::ADDRESS_SPACE_DATA => "data",
+        AddressSpace::ADDRESS_SPACE_IO => "io",
+        AddressSpace::ADDRESS_SPACE_OPCODES => "opcodes",
+    }
+}
+
+fn parse_number(val: &str) -> Result<u64, String> {
+    let parsed = if val.starts_with("0x") {
+        u64::from_str_radix(&val[2..], 16)
+    } else {
+        val.parse()
+    };
+    parsed.map_err(|e| format!("invalid number {}: {}", val, e))
+}
+
+// args: cpu tag, address space, address and width (ex, :maincpu program 0xff8000 2)
+fn parse_location(args: &[String]) -> Result<MemoryLocation, String> {
+    if args.len() < 4 {
+        return Err(format!("invalid memory location: {:?}", args));
+    }
+
+    let location = MemoryLocation {
+        cpu_tag: args[0].clone(),
+        space: match &args[1][..] {
+            "program" => AddressSpace::ADDRESS_SPACE_PROGRAM,
+            "data" => AddressSpace::ADDRESS_SPACE_DATA,
+            "io" => AddressSpace::ADDRESS_SPACE_IO,
+            "opcodes" => AddressSpace::ADDRESS_SPACE_OPCODES,
+            _ => return Err(format!("invalid address space: {}", args[1])),
+        },
+        address: parse_number(&args[2])?,
+        width: parse_number(&args[3])? as usize,
+    };
+    location.validate()?;
+    Ok(location)
+}
+
+// Handles the 'mem' commands. Watched locations are polled on a thread of their own,
+// reports of both reads and watches go out through report_tx.
+pub struct MemCmdHandler {
+    access: MemAccess,
+    watch_tx: channel::Sender<WatchCmd>,
+    report_tx: channel::Sender<MemoryReport>,
+}
+
+impl MemCmdHandler {
+    pub fn create(
+        access: MemAccess,
+        emu: impl Emulator + 'static,
+        report_tx: channel::Sender<MemoryReport>) -> MemCmdHandler {
+
+        let (watch_tx, watch_rx) = channel::unbounded();
+        if access != MemAccess::Off {
+            run_mem_watcher(emu, watch_rx, report_tx.clone());
+        }
+
+        MemCmdHandler {
+            access: access,
+            watch_tx: watch_tx,
+            report_tx: report_tx,
+        }
+    }
+
+    pub fn handle(&self, emu: &impl Emulator, args: &Vec<String>) -> Result<(), String> {
+        let op = args.get(0).map(|s| s.as_str()).unwrap_or("");
+        match (op, self.access) {
+            (_, MemAccess::Off) => return Err(format!("memory commands are disabled")),
+            ("write", MemAccess::Read) => return Err(format!("memory writes are disabled")),
+            _ => {},
+        }
+
+        match op {
+            "read" => {
+                let location = parse_location(&args[1..])?;
+                let value = emu.read_memory(&location)?;
+                self.report_tx.send(MemoryReport::from("read", &location, value)).unwrap();
+            },
+            "write" => {
+                let location = parse_location(&args[1..])?;
+                let value = parse_number(args.get(5).map(|s| s.as_str()).unwrap_or(""))?;
+                emu.write_memory(&location, value)?;
+            },
+            "watch" => {
+                if args.len() < 6 {
+                    return Err(format!("invalid watch args: {:?}", args));
+                }
+                let location = parse_location(&args[2..])?;
+                let interval_ms = match args.get(6) {
+                    Some(val) => parse_number(val)?,
+                    None => DEFAULT_WATCH_INTERVAL_MS,
+                };
+                let interval = Duration::from_millis(interval_ms);
+                self.watch_tx.send(WatchCmd::Watch(args[1].clone(), location, interval)).unwrap();
+            },
+            "unwatch" => match args.get(1) {
+                Some(id) => self.watch_tx.send(WatchCmd::Unwatch(id.clone())).unwrap(),
+                None => return Err(format!("watch id is missing")),
+            },
+            _ => return Err(format!("not supported mem op: {}", op)),
+        }
+
+        Ok(())
+    }
+}
+
+// Polls every watch at its own interval and reports the value when it has changed,
+// including the first poll after the watch is set.
+fn run_mem_watcher(
+    emu: impl Emulator + 'static,
+    watch_rx: channel::Receiver<WatchCmd>,
+    report_tx: channel::Sender<MemoryReport>) {
+
+    thread::spawn(move || {
+        let mut watches: HashMap<String, Watch> = HashMap::new();
+
+        loop {
+            match watch_rx.recv_timeout(WATCH_POLL_INTERVAL) {
+                Ok(WatchCmd::Watch(id, location, interval)) => {
+                    watches.insert(id, Watch {
+                        location: location,
+                        interval: interval,
+                        next_poll: Instant::now(),
+                        last_value: None,
+                    });
+                },
+                Ok(WatchCmd::Unwatch(id)) => {
+                    watches.remove(&id);
+                },
+                Err(channel::RecvTimeoutError::Timeout) => {},
+                Err(channel::RecvTimeoutError::Disconnected) => break,
+            }
+
+            let now = Instant::now();
+            let mut failed = vec![];
+            for (id, watch) in watches.iter_mut().filter(|(_, w)| w.next_poll <= now) {
+                watch.next_poll = now + watch.interval;
+                match emu.read_memory(&watch.location) {
+                    Ok(value) if watch.last_value != Some(value) => {
+                        watch.last_value = Some(value);
+                        report_tx.send(MemoryReport::from(id, &watch.location, value)).unwrap();
+                    },
+                    Ok(_) => {},
+                    Err(msg) => {
+                        println!("dropping watch {}: {}", id, msg);
+                        failed.push(id.clone());
+                    },
+                }
+            }
+            for id in failed {
+                watches.remove(&id);
+            }
+        }
+    });
+}
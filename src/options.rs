@@ -0,0 +1,74 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use serde::Deserialize;
+
+use libemu::EmulatorOptions;
+
+// Options file, where the options of a system override the defaults
+// {
+//   "default": { "rompath": "./roms", "nvram_directory": "./nvram", "cfg_directory": "./cfg" },
+//   "systems": {
+//     "dino": { "bgfx_screen_chains": "crt-geom", "extra": { "bgfx_path": "./bgfx" } }
+//   }
+// }
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct OptionsFile {
+    default: OptionsEntry,
+    systems: HashMap<String, OptionsEntry>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct OptionsEntry {
+    rompath: Option<String>,
+    nvram_directory: Option<String>,
+    cfg_directory: Option<String>,
+    state_directory: Option<String>,
+    bgfx_screen_chains: Option<String>,
+    video: Option<String>,
+    sample_rate: Option<usize>,
+    run_ahead: Option<u32>,
+    extra: BTreeMap<String, String>,
+}
+
+impl OptionsEntry {
+    fn to_options(&self) -> EmulatorOptions {
+        EmulatorOptions {
+            rompath: self.rompath.clone(),
+            nvram_directory: self.nvram_directory.clone(),
+            cfg_directory: self.cfg_directory.clone(),
+            state_directory: self.state_directory.clone(),
+            bgfx_screen_chains: self.bgfx_screen_chains.clone(),
+            video: self.video.clone(),
+            sample_rate: self.sample_rate,
+            run_ahead: self.run_ahead,
+            extra: self.extra.iter()
+                .map(|(name, value)| (String::from(name.trim_start_matches('-')), value.clone()))
+                .collect(),
+        }
+    }
+}
+
+pub fn load_options(path: &str, system_name: &str) -> Result<EmulatorOptions, String> {
+    let json_str = fs::read_to_string(path).map_err(|e| format!("failed to read options {}: {}", path, e))?;
+    let file: OptionsFile = serde_json::from_str(&json_str)
+        .map_err(|e| format!("invalid options {}: {}", path, e))?;
+
+    let options = file.default.to_options();
+    match file.systems.get(system_name) {
+        Some(entry) => Ok(options.overlay(&entry.to_options())),
+        None => Ok(options),
+    }
+}
+
+// name=value, with or without the leading dash of the mame command line (ex, -artwork_crop=1)
+pub fn parse_extra_option(arg: &str) -> Result<(String, String), String> {
+    match arg.find('=') {
+        Some(idx) if idx > 0 => Ok((
+            String::from(arg[..idx].trim_start_matches('-')),
+            String::from(&arg[idx + 1..]))),
+        _ => Err(format!("invalid mame option: {}", arg)),
+    }
+}